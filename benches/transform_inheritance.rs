@@ -0,0 +1,142 @@
+//! Measures `SystemTransformInheritance` on a scene of 100k entities, of which only
+//! a few, or all, are modified between runs. The `baseline_*` cases run the same scenarios
+//! with `SystemTransformInheritanceBaseline`, which recomputes every absolute transform.
+
+#![feature(test)]
+extern crate test;
+
+use test::Bencher;
+use ammolite_math::Mat4;
+use specs::prelude::*;
+use specs_hierarchy::{Hierarchy, HierarchySystem};
+use metaview_lib::ecs::*;
+use metaview_lib::ecs::animation::SystemAnimation;
+
+const GROUP_COUNT: usize = 1_000;
+const GROUP_SIZE: usize = 100;
+
+/// The transform inheritance as it was before it became incremental: the absolute transform
+/// of every entity in the hierarchy is recomputed on every run.
+struct SystemTransformInheritanceBaseline;
+
+impl<'a> System<'a> for SystemTransformInheritanceBaseline {
+    type SystemData = (
+        ReadExpect<'a, Hierarchy<ComponentParent>>,
+        ReadExpect<'a, ResourceSceneRoot>,
+        ReadStorage<'a, ComponentParent>,
+        ReadStorage<'a, ComponentTransformRelative>,
+        WriteStorage<'a, ComponentTransformAbsolute>,
+    );
+
+    fn run(&mut self, (hierarchy, scene_root, parent, transform_rel, mut transform_abs): Self::SystemData) {
+        for entity in hierarchy.all_children_iter(scene_root.0) {
+            if let Some(transform_rel) = transform_rel.get(entity) {
+                let mut matrix_abs_new = transform_rel.matrix.clone();
+
+                if let Some(parent) = parent.get(entity) {
+                    if let Some(parent_transform_abs) = transform_abs.get(parent.entity) {
+                        matrix_abs_new = matrix_abs_new * &parent_transform_abs.matrix;
+                    }
+                }
+
+                transform_abs.insert(entity, ComponentTransformAbsolute {
+                    matrix: matrix_abs_new,
+                }).unwrap();
+            }
+        }
+    }
+}
+
+/// Builds a scene of `GROUP_COUNT` groups under the scene root, each being a chain of
+/// `GROUP_SIZE` entities, so that the hierarchy is both wide and deep.
+fn build_scene(baseline: bool) -> (World, Dispatcher<'static, 'static>, Vec<Entity>) {
+    let mut world = World::new();
+    let dispatcher_builder = DispatcherBuilder::new()
+        .with(HierarchySystem::<ComponentParent>::new(&mut world), "system_hierarchy", &[])
+        .with(SystemAnimation, "system_animation", &[])
+        .with(SystemTransformCompose::default(), "system_transform_compose", &["system_animation"])
+        .with_barrier();
+    let mut dispatcher = if baseline {
+        dispatcher_builder.with(SystemTransformInheritanceBaseline, "system_transform_inheritance", &[])
+    } else {
+        dispatcher_builder.with(SystemTransformInheritance::default(), "system_transform_inheritance", &[])
+    }.build();
+
+    dispatcher.setup(&mut world);
+
+    let scene_root = world.create_entity()
+        .build();
+
+    world.insert(ResourceSceneRoot(scene_root));
+
+    let mut entities = Vec::with_capacity(GROUP_COUNT * GROUP_SIZE);
+
+    for _ in 0..GROUP_COUNT {
+        let mut parent = scene_root;
+
+        for _ in 0..GROUP_SIZE {
+            parent = world.create_entity()
+                .with(ComponentParent { entity: parent })
+                .with(ComponentTransformRelative { matrix: Mat4::IDENTITY })
+                .build();
+
+            entities.push(parent);
+        }
+    }
+
+    // Propagate the initial transforms.
+    dispatcher.dispatch(&mut world);
+    world.maintain();
+
+    (world, dispatcher, entities)
+}
+
+fn bench_modified(bencher: &mut Bencher, modified_count: usize, baseline: bool) {
+    let (mut world, mut dispatcher, entities) = build_scene(baseline);
+    let step = entities.len() / modified_count.max(1);
+
+    bencher.iter(|| {
+        {
+            let mut transforms = world.write_storage::<ComponentTransformRelative>();
+
+            for entity in entities.iter().step_by(step).take(modified_count) {
+                transforms.get_mut(*entity)
+                    .expect("Every entity has a relative transform.")
+                    .matrix = Mat4::IDENTITY;
+            }
+        }
+
+        dispatcher.dispatch(&mut world);
+        world.maintain();
+    });
+}
+
+#[bench]
+fn static_scene(bencher: &mut Bencher) {
+    bench_modified(bencher, 0, false);
+}
+
+#[bench]
+fn few_modified(bencher: &mut Bencher) {
+    bench_modified(bencher, 10, false);
+}
+
+#[bench]
+fn all_modified(bencher: &mut Bencher) {
+    bench_modified(bencher, GROUP_COUNT * GROUP_SIZE, false);
+}
+
+#[bench]
+fn baseline_static_scene(bencher: &mut Bencher) {
+    bench_modified(bencher, 0, true);
+}
+
+#[bench]
+fn baseline_few_modified(bencher: &mut Bencher) {
+    bench_modified(bencher, 10, true);
+}
+
+#[bench]
+fn baseline_all_modified(bencher: &mut Bencher) {
+    bench_modified(bencher, GROUP_COUNT * GROUP_SIZE, true);
+}
@@ -0,0 +1,364 @@
+use std::time::Duration;
+use ammolite_math::{Vec3, Quaternion};
+use specs::prelude::*;
+use crate::ecs::{ComponentTransformDecomposed, ResourceTimeElapsed};
+
+/// How the value approaches a keyframe from the previous one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    /// Keeps the previous value until the keyframe is reached.
+    Step,
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps the linear progress between two keyframes, within `0..=1`, to the eased one.
+    pub fn apply(self, progress: f32) -> f32 {
+        match self {
+            Easing::Step => if progress < 1.0 { 0.0 } else { 1.0 },
+            Easing::Linear => progress,
+            Easing::EaseIn => progress * progress,
+            Easing::EaseOut => progress * (2.0 - progress),
+            Easing::EaseInOut => progress * progress * (3.0 - 2.0 * progress),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keyframe<T> {
+    /// The time since the start of the clip, in seconds.
+    pub time: f32,
+    pub value: T,
+    /// The easing of the transition from the previous keyframe to this one.
+    pub easing: Easing,
+}
+
+/// Keyframe tracks for the parts of `ComponentTransformDecomposed`, each sorted by time.
+/// A part without keyframes is left as it is.
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub translation: Vec<Keyframe<Vec3>>,
+    pub rotation: Vec<Keyframe<Quaternion>>,
+    pub scale: Vec<Keyframe<Vec3>>,
+}
+
+impl AnimationClip {
+    /// The time of the last keyframe, in seconds.
+    pub fn duration(&self) -> f32 {
+        let last_time = |track: Option<f32>| track.unwrap_or(0.0);
+
+        last_time(self.translation.last().map(|keyframe| keyframe.time))
+            .max(last_time(self.rotation.last().map(|keyframe| keyframe.time)))
+            .max(last_time(self.scale.last().map(|keyframe| keyframe.time)))
+    }
+
+    /// Checks that the keyframe times are non-negative and in order.
+    pub fn is_valid(&self) -> bool {
+        fn is_track_valid<T>(track: &[Keyframe<T>]) -> bool {
+            track.iter().all(|keyframe| keyframe.time >= 0.0)
+                && track.windows(2).all(|pair| pair[0].time <= pair[1].time)
+        }
+
+        is_track_valid(&self.translation) && is_track_valid(&self.rotation) && is_track_valid(&self.scale)
+    }
+}
+
+fn sample<T: Clone>(track: &[Keyframe<T>], time: f32, interpolate: impl Fn(&T, &T, f32) -> T) -> Option<T> {
+    match track.iter().position(|keyframe| keyframe.time > time) {
+        None => track.last().map(|keyframe| keyframe.value.clone()),
+        Some(0) => Some(track[0].value.clone()),
+        Some(index) => {
+            let from = &track[index - 1];
+            let to = &track[index];
+            let progress = (time - from.time) / (to.time - from.time);
+
+            Some(interpolate(&from.value, &to.value, to.easing.apply(progress)))
+        },
+    }
+}
+
+fn lerp(from: &Vec3, to: &Vec3, t: f32) -> Vec3 {
+    let mut result = [0.0; 3];
+
+    for (component, (from, to)) in result.iter_mut().zip(from.0.iter().zip(to.0.iter())) {
+        *component = from + (to - from) * t;
+    }
+
+    Vec3(result)
+}
+
+/// Interpolates along the shorter arc between the rotations, at a constant angular velocity.
+fn slerp(from: &Quaternion, to: &Quaternion, t: f32) -> Quaternion {
+    let from = from.0;
+    let mut to = to.0;
+    let mut cos_angle: f32 = from.iter().zip(to.iter()).map(|(from, to)| from * to).sum();
+
+    // `to` and its negation represent the same rotation; the one closer to `from` takes the
+    // shorter arc.
+    if cos_angle < 0.0 {
+        to = [-to[0], -to[1], -to[2], -to[3]];
+        cos_angle = -cos_angle;
+    }
+
+    // Nearly parallel rotations are interpolated linearly, to avoid dividing by a vanishing sine.
+    let (from_weight, to_weight) = if cos_angle > 0.9995 {
+        (1.0 - t, t)
+    } else {
+        let angle = cos_angle.min(1.0).acos();
+        let sin_angle = angle.sin();
+
+        (((1.0 - t) * angle).sin() / sin_angle, (t * angle).sin() / sin_angle)
+    };
+    let mut result = [0.0; 4];
+
+    for (component, (from, to)) in result.iter_mut().zip(from.iter().zip(to.iter())) {
+        *component = from * from_weight + to * to_weight;
+    }
+
+    let norm = result.iter().map(|component| component * component).sum::<f32>().sqrt();
+
+    if norm > 0.0 {
+        for component in &mut result {
+            *component /= norm;
+        }
+    }
+
+    Quaternion(result)
+}
+
+/// Animates `ComponentTransformDecomposed`, which the entity must have.
+pub struct ComponentAnimation {
+    pub clip: AnimationClip,
+    /// The `ResourceTimeElapsed` at which the animation started.
+    pub start: Duration,
+    /// Whether the clip restarts once it ends, rather than being removed.
+    pub looping: bool,
+}
+
+impl Component for ComponentAnimation {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// The entities whose non-looping animations ended since the resource was last drained.
+#[derive(Default)]
+pub struct ResourceFinishedAnimations(pub Vec<Entity>);
+
+pub struct SystemAnimation;
+
+impl<'a> System<'a> for SystemAnimation {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, ResourceTimeElapsed>,
+        Write<'a, ResourceFinishedAnimations>,
+        WriteStorage<'a, ComponentAnimation>,
+        WriteStorage<'a, ComponentTransformDecomposed>,
+    );
+
+    fn run(&mut self, (entities, time_elapsed, mut finished_animations, mut animation, mut transform): Self::SystemData) {
+        // The entities whose animations ended, along with whether to report them as finished.
+        let mut ended = Vec::new();
+
+        for (entity, animation, transform) in (&entities, &animation, &mut transform).join() {
+            let duration = animation.clip.duration();
+            let mut time = time_elapsed.0.checked_sub(animation.start)
+                .unwrap_or_default()
+                .as_secs_f32();
+
+            if animation.looping && duration > 0.0 {
+                time %= duration;
+            } else if time >= duration {
+                time = duration;
+                // A looping clip without a duration holds a single pose, which only has to be
+                // applied once. It never finishes, so it is not reported.
+                ended.push((entity, !animation.looping));
+            }
+
+            if let Some(translation) = sample(&animation.clip.translation, time, lerp) {
+                transform.translation = translation;
+            }
+
+            if let Some(rotation) = sample(&animation.clip.rotation, time, slerp) {
+                transform.rotation = rotation;
+            }
+
+            if let Some(scale) = sample(&animation.clip.scale, time, lerp) {
+                transform.scale = scale;
+            }
+        }
+
+        for (entity, finished) in ended {
+            animation.remove(entity);
+
+            if finished {
+                finished_animations.0.push(entity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{World, WorldExt, Builder, RunNow};
+    use super::*;
+
+    const EASINGS: [Easing; 5] = [Easing::Step, Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut];
+
+    fn keyframe(time: f32, value: f32, easing: Easing) -> Keyframe<f32> {
+        Keyframe { time, value, easing }
+    }
+
+    fn interpolate(from: &f32, to: &f32, t: f32) -> f32 {
+        from + (to - from) * t
+    }
+
+    #[test]
+    fn easings_keep_the_endpoints_and_are_monotonic() {
+        for easing in &EASINGS {
+            assert_eq!(easing.apply(0.0), 0.0, "{:?}", easing);
+            assert_eq!(easing.apply(1.0), 1.0, "{:?}", easing);
+
+            for step in 0..100 {
+                let progress = step as f32 / 100.0;
+
+                assert!(easing.apply(progress) <= easing.apply(progress + 0.01), "{:?}", easing);
+            }
+        }
+    }
+
+    #[test]
+    fn easings_shape_the_progress() {
+        assert_eq!(Easing::Step.apply(0.99), 0.0);
+        assert_eq!(Easing::Linear.apply(0.25), 0.25);
+        assert!(Easing::EaseIn.apply(0.5) < 0.5);
+        assert!(Easing::EaseOut.apply(0.5) > 0.5);
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert!(Easing::EaseInOut.apply(0.25) < 0.25);
+        assert!(Easing::EaseInOut.apply(0.75) > 0.75);
+    }
+
+    #[test]
+    fn sample_clamps_to_the_track() {
+        let track = [keyframe(1.0, 10.0, Easing::Linear), keyframe(3.0, 30.0, Easing::Linear)];
+
+        assert_eq!(sample::<f32>(&[], 1.0, interpolate), None);
+        assert_eq!(sample(&track, 0.0, interpolate), Some(10.0));
+        assert_eq!(sample(&track, 1.0, interpolate), Some(10.0));
+        assert_eq!(sample(&track, 3.0, interpolate), Some(30.0));
+        assert_eq!(sample(&track, 5.0, interpolate), Some(30.0));
+    }
+
+    #[test]
+    fn sample_applies_the_easing_of_the_next_keyframe() {
+        let track = [
+            keyframe(0.0, 0.0, Easing::Step),
+            keyframe(2.0, 20.0, Easing::Linear),
+            keyframe(4.0, 40.0, Easing::Step),
+            keyframe(6.0, 60.0, Easing::EaseIn),
+        ];
+
+        assert_eq!(sample(&track, 1.0, interpolate), Some(10.0));
+        assert_eq!(sample(&track, 3.0, interpolate), Some(20.0));
+        assert_eq!(sample(&track, 4.0, interpolate), Some(40.0));
+        assert_eq!(sample(&track, 5.0, interpolate), Some(45.0));
+    }
+
+    #[test]
+    fn sample_jumps_at_keyframes_with_equal_times() {
+        let track = [
+            keyframe(0.0, 0.0, Easing::Linear),
+            keyframe(1.0, 10.0, Easing::Linear),
+            keyframe(1.0, 100.0, Easing::Linear),
+            keyframe(2.0, 200.0, Easing::Linear),
+        ];
+
+        assert_eq!(sample(&track, 0.5, interpolate), Some(5.0));
+        assert_eq!(sample(&track, 1.0, interpolate), Some(100.0));
+        assert_eq!(sample(&track, 1.5, interpolate), Some(150.0));
+    }
+
+    fn animate(clip: AnimationClip, looping: bool, elapsed: Duration) -> (World, Entity) {
+        let mut world = World::new();
+
+        world.register::<ComponentAnimation>();
+        world.register::<ComponentTransformDecomposed>();
+        world.insert(ResourceTimeElapsed(elapsed));
+        world.insert(ResourceFinishedAnimations::default());
+
+        let entity = world.create_entity()
+            .with(ComponentTransformDecomposed {
+                translation: Vec3([0.0, 0.0, 0.0]),
+                rotation: Quaternion([0.0, 0.0, 0.0, 1.0]),
+                scale: Vec3([1.0, 1.0, 1.0]),
+            })
+            .with(ComponentAnimation { clip, start: Duration::from_secs(0), looping })
+            .build();
+
+        SystemAnimation.run_now(&world);
+        world.maintain();
+
+        (world, entity)
+    }
+
+    #[test]
+    fn slerp_takes_the_shorter_arc_at_a_constant_rate() {
+        let angle = std::f32::consts::FRAC_PI_2;
+        let identity = Quaternion([0.0, 0.0, 0.0, 1.0]);
+        let quarter_turn = Quaternion([0.0, 0.0, (angle / 2.0).sin(), (angle / 2.0).cos()]);
+        let eighth_turn = [0.0, 0.0, (angle / 4.0).sin(), (angle / 4.0).cos()];
+        let assert_close = |actual: Quaternion, expected: [f32; 4]| {
+            for (actual, expected) in actual.0.iter().zip(expected.iter()) {
+                assert!((actual - expected).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+            }
+        };
+
+        assert_close(slerp(&identity, &quarter_turn, 0.0), identity.0);
+        assert_close(slerp(&identity, &quarter_turn, 1.0), quarter_turn.0);
+        assert_close(slerp(&identity, &quarter_turn, 0.5), eighth_turn);
+
+        let negated_quarter_turn = Quaternion([-quarter_turn.0[0], -quarter_turn.0[1], -quarter_turn.0[2], -quarter_turn.0[3]]);
+
+        assert_close(slerp(&identity, &negated_quarter_turn, 0.5), eighth_turn);
+        assert_close(slerp(&identity, &identity, 0.5), identity.0);
+    }
+
+    fn translation_clip(times: &[f32]) -> AnimationClip {
+        AnimationClip {
+            translation: times.iter()
+                .map(|time| Keyframe { time: *time, value: Vec3([*time, 0.0, 0.0]), easing: Easing::Linear })
+                .collect(),
+            rotation: Vec::new(),
+            scale: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn looping_clips_without_duration_are_applied_once_without_finishing() {
+        let mut clip = translation_clip(&[0.0]);
+
+        clip.translation[0].value = Vec3([5.0, 0.0, 0.0]);
+
+        let (world, entity) = animate(clip, true, Duration::from_secs(1));
+
+        assert!(!world.read_storage::<ComponentAnimation>().contains(entity));
+        assert!(world.read_resource::<ResourceFinishedAnimations>().0.is_empty());
+        assert_eq!(world.read_storage::<ComponentTransformDecomposed>().get(entity).unwrap().translation.0, [5.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn clips_finish_unless_looping() {
+        let (world, entity) = animate(translation_clip(&[0.0, 2.0]), false, Duration::from_secs(3));
+
+        assert!(!world.read_storage::<ComponentAnimation>().contains(entity));
+        assert_eq!(world.read_resource::<ResourceFinishedAnimations>().0, vec![entity]);
+        assert_eq!(world.read_storage::<ComponentTransformDecomposed>().get(entity).unwrap().translation.0, [2.0, 0.0, 0.0]);
+
+        let (world, entity) = animate(translation_clip(&[0.0, 2.0]), true, Duration::from_secs(3));
+
+        assert!(world.read_storage::<ComponentAnimation>().contains(entity));
+        assert!(world.read_resource::<ResourceFinishedAnimations>().0.is_empty());
+        assert_eq!(world.read_storage::<ComponentTransformDecomposed>().get(entity).unwrap().translation.0, [1.0, 0.0, 0.0]);
+    }
+}
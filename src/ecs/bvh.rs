@@ -0,0 +1,1008 @@
+//! A bounding volume hierarchy over the world-space bounds of the models, used to accelerate
+//! ray casts and other spatial queries.
+//!
+//! The hierarchy is refitted as items move, are added or removed, and is only rebuilt once
+//! the refits have degraded it too much, see `Bvh::rebuild_if_degraded`.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use ammolite_math::{Mat4, Vec3};
+use specs::prelude::*;
+use specs::storage::ComponentEvent;
+use specs::world::Index;
+use crate::ecs::{ComponentModel, ComponentTransformAbsolute};
+
+/// The number of items below which a node is not split any further.
+const LEAF_SIZE: usize = 4;
+
+/// How much the total surface area of the nodes may grow through refits, relative to the
+/// last build, before the hierarchy is rebuilt.
+const MAX_COST_GROWTH: f32 = 2.0;
+
+/// The fraction of the items that may have been inserted or removed since the last build,
+/// before the hierarchy is rebuilt, as inserted items are not placed as well as by a build.
+const MAX_CHURN: f32 = 0.25;
+
+pub fn transform_point(matrix: &Mat4, point: [f32; 3]) -> [f32; 3] {
+    (matrix * Vec3(point).into_homogeneous_position()).into_projected().0
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// An affine transform, which maps a point to the sum of the translation and the axes scaled by
+/// the point's coordinates. Unlike a `Mat4`, it can be inverted, to bring world-space queries
+/// into the local space of a model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Affine {
+    pub axes: [[f32; 3]; 3],
+    pub translation: [f32; 3],
+}
+
+impl Affine {
+    /// Reads the affine part of the matrix, from the images of the origin and the unit axes.
+    pub fn from_matrix(matrix: &Mat4) -> Self {
+        let translation = transform_point(matrix, [0.0; 3]);
+        let axis = |unit: [f32; 3]| {
+            let image = transform_point(matrix, unit);
+
+            [image[0] - translation[0], image[1] - translation[1], image[2] - translation[2]]
+        };
+
+        Affine {
+            axes: [axis([1.0, 0.0, 0.0]), axis([0.0, 1.0, 0.0]), axis([0.0, 0.0, 1.0])],
+            translation,
+        }
+    }
+
+    pub fn transform_vector(&self, vector: [f32; 3]) -> [f32; 3] {
+        let mut result = [0.0; 3];
+
+        for (axis, component) in self.axes.iter().zip(vector.iter()) {
+            for row in 0..3 {
+                result[row] += axis[row] * component;
+            }
+        }
+
+        result
+    }
+
+    pub fn transform_point(&self, point: [f32; 3]) -> [f32; 3] {
+        let vector = self.transform_vector(point);
+
+        [vector[0] + self.translation[0], vector[1] + self.translation[1], vector[2] + self.translation[2]]
+    }
+
+    /// Returns the inverse transform, or `None`, if the transform collapses space onto a plane,
+    /// a line or a point.
+    pub fn inverse(&self) -> Option<Affine> {
+        let [a, b, c] = self.axes;
+        let determinant = dot(a, cross(b, c));
+
+        if determinant == 0.0 || !determinant.is_finite() {
+            return None;
+        }
+
+        // The rows of the inverse are the cross products of the columns, over the determinant.
+        let rows = [cross(b, c), cross(c, a), cross(a, b)];
+        let mut inverse = Affine {
+            axes: [[0.0; 3]; 3],
+            translation: [0.0; 3],
+        };
+
+        for (column, axis) in inverse.axes.iter_mut().enumerate() {
+            for (component, row) in axis.iter_mut().zip(rows.iter()) {
+                *component = row[column] / determinant;
+            }
+        }
+
+        let translation = inverse.transform_vector(self.translation);
+
+        inverse.translation = [-translation[0], -translation[1], -translation[2]];
+
+        Some(inverse)
+    }
+
+    /// The bounds of the box transformed.
+    pub fn transform_bounds(&self, bounds: &Aabb) -> Aabb {
+        if bounds.is_empty() {
+            return *bounds;
+        }
+
+        bounds.corners().iter()
+            .fold(Aabb::EMPTY, |result, corner| result.union_point(self.transform_point(*corner)))
+    }
+}
+
+/// An axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: [std::f32::INFINITY; 3],
+        max: [std::f32::NEG_INFINITY; 3],
+    };
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.min[axis] > self.max[axis])
+    }
+
+    pub fn corners(&self) -> [[f32; 3]; 8] {
+        let mut corners = [[0.0; 3]; 8];
+
+        for (index, corner) in corners.iter_mut().enumerate() {
+            *corner = [
+                if index & 1 == 0 { self.min[0] } else { self.max[0] },
+                if index & 2 == 0 { self.min[1] } else { self.max[1] },
+                if index & 4 == 0 { self.min[2] } else { self.max[2] },
+            ];
+        }
+
+        corners
+    }
+
+    /// The bounds of the box transformed by `matrix`.
+    pub fn transformed(&self, matrix: &Mat4) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        self.corners().iter()
+            .fold(Aabb::EMPTY, |bounds, corner| bounds.union_point(transform_point(matrix, *corner)))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut result = *self;
+
+        for axis in 0..3 {
+            result.min[axis] = result.min[axis].min(other.min[axis]);
+            result.max[axis] = result.max[axis].max(other.max[axis]);
+        }
+
+        result
+    }
+
+    pub fn union_point(&self, point: [f32; 3]) -> Aabb {
+        self.union(&Aabb { min: point, max: point })
+    }
+
+    pub fn centroid(&self) -> [f32; 3] {
+        [
+            (self.min[0] + self.max[0]) * 0.5,
+            (self.min[1] + self.max[1]) * 0.5,
+            (self.min[2] + self.max[2]) * 0.5,
+        ]
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let extent = [self.max[0] - self.min[0], self.max[1] - self.min[1], self.max[2] - self.min[2]];
+
+        2.0 * (extent[0] * extent[1] + extent[1] * extent[2] + extent[2] * extent[0])
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.max[axis] && other.min[axis] <= self.max[axis])
+    }
+
+    /// Returns the distance along the ray at which it enters the box, or `0.0`, if the origin
+    /// lies within the box. The direction is given by its component-wise inverse.
+    pub fn intersect_ray(&self, origin: [f32; 3], direction_inverse: [f32; 3]) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut entry = 0.0f32;
+        let mut exit = std::f32::INFINITY;
+
+        for axis in 0..3 {
+            let near = (self.min[axis] - origin[axis]) * direction_inverse[axis];
+            let far = (self.max[axis] - origin[axis]) * direction_inverse[axis];
+
+            // NaN, from a zero direction component on the boundary of the box, is ignored.
+            entry = entry.max(near.min(far));
+            exit = exit.min(near.max(far));
+        }
+
+        if entry <= exit {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Aabb::EMPTY
+    }
+}
+
+/// Where a triangle of a `ModelGeometry` comes from in the glTF document.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TriangleSource {
+    pub mesh_index: usize,
+    /// The index of the primitive within the mesh.
+    pub primitive_index: usize,
+    /// The index of the triangle within the primitive.
+    pub triangle_index: usize,
+    /// The texture coordinates of the vertices, from the first set, if the primitive has any.
+    pub texcoords: Option<[[f32; 2]; 3]>,
+}
+
+/// Where a ray hits a triangle of a `ModelGeometry`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriangleHit {
+    /// The distance along the ray, in multiples of its direction.
+    pub distance: f32,
+    /// The index of the triangle in `ModelGeometry::triangles`.
+    pub triangle: usize,
+    /// The weights of the triangle's second and third vertices at the hit; the first vertex
+    /// has the remaining weight.
+    pub barycentric: [f32; 2],
+}
+
+/// The triangles of a model in its local space, read from its glTF document when the model
+/// is loaded, with the node transforms of its default scene applied.
+#[derive(Debug, Clone, Default)]
+pub struct ModelGeometry {
+    pub bounds: Aabb,
+    pub triangles: Vec<[[f32; 3]; 3]>,
+    /// The source of each of the triangles, in the same order.
+    pub sources: Vec<TriangleSource>,
+}
+
+impl ModelGeometry {
+    pub fn new(triangles: Vec<[[f32; 3]; 3]>, sources: Vec<TriangleSource>) -> Self {
+        assert_eq!(triangles.len(), sources.len(), "Every triangle must have a source.");
+
+        let bounds = triangles.iter()
+            .flat_map(|triangle| triangle.iter())
+            .fold(Aabb::EMPTY, |bounds, vertex| bounds.union_point(*vertex));
+
+        Self { bounds, triangles, sources }
+    }
+
+    /// Returns the closest hit of the ray on either side of the triangles, given in the
+    /// model's space.
+    pub fn intersect_ray(&self, origin: [f32; 3], direction: [f32; 3]) -> Option<TriangleHit> {
+        let direction_inverse = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+
+        self.bounds.intersect_ray(origin, direction_inverse)?;
+
+        let mut closest: Option<TriangleHit> = None;
+
+        // Möller–Trumbore
+        for (index, [a, b, c]) in self.triangles.iter().enumerate() {
+            let edge_b = sub(*b, *a);
+            let edge_c = sub(*c, *a);
+            let p = cross(direction, edge_c);
+            let determinant = dot(edge_b, p);
+
+            // The ray is parallel to the triangle, or the triangle is degenerate.
+            if determinant == 0.0 {
+                continue;
+            }
+
+            let to_origin = sub(origin, *a);
+            let u = dot(to_origin, p) / determinant;
+
+            if !(0.0..=1.0).contains(&u) {
+                continue;
+            }
+
+            let q = cross(to_origin, edge_b);
+            let v = dot(direction, q) / determinant;
+
+            if !(v >= 0.0 && u + v <= 1.0) {
+                continue;
+            }
+
+            let distance = dot(edge_c, q) / determinant;
+
+            if distance >= 0.0 && closest.map(|closest| distance < closest.distance).unwrap_or(true) {
+                closest = Some(TriangleHit { distance, triangle: index, barycentric: [u, v] });
+            }
+        }
+
+        closest
+    }
+
+    /// The unit normal of the triangle transformed by `affine`, on the side facing against
+    /// `direction`.
+    pub fn facing_normal(&self, triangle: usize, affine: &Affine, direction: [f32; 3]) -> [f32; 3] {
+        let [a, b, c] = self.triangles[triangle];
+        let a = affine.transform_point(a);
+        let normal = cross(sub(affine.transform_point(b), a), sub(affine.transform_point(c), a));
+        let length = dot(normal, normal).sqrt();
+        let scale = if dot(normal, direction) > 0.0 { -1.0 / length } else { 1.0 / length };
+
+        [normal[0] * scale, normal[1] * scale, normal[2] * scale]
+    }
+
+    /// Interpolates the texture coordinates of the triangle at the hit, if it has any.
+    pub fn texcoord(&self, hit: &TriangleHit) -> Option<[f32; 2]> {
+        let [a, b, c] = self.sources[hit.triangle].texcoords?;
+        let [u, v] = hit.barycentric;
+        let w = 1.0 - u - v;
+
+        Some([
+            a[0] * w + b[0] * u + c[0] * v,
+            a[1] * w + b[1] * u + c[1] * v,
+        ])
+    }
+}
+
+enum BvhNode {
+    Branch { bounds: Aabb, children: [usize; 2] },
+    /// The slots of the items in the leaf.
+    Leaf { bounds: Aabb, items: Vec<usize> },
+}
+
+impl BvhNode {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            BvhNode::Branch { bounds, .. } | BvhNode::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+pub struct BvhItem<T> {
+    /// Identifies the item, for it to be updated or removed.
+    pub key: Index,
+    pub bounds: Aabb,
+    pub value: T,
+}
+
+pub struct Bvh<T> {
+    nodes: Vec<BvhNode>,
+    /// The parent of each node, `None` for the root node.
+    node_parents: Vec<Option<usize>>,
+    items: Vec<BvhItem<T>>,
+    /// The leaf each item is in, indexed like `items`.
+    item_leaves: Vec<usize>,
+    /// The index of each item in `items`, by key.
+    slots: HashMap<Index, usize>,
+    /// The cost of the hierarchy right after it was last built, see `cost`.
+    built_cost: f32,
+    /// The number of items inserted or removed since the hierarchy was last built.
+    churn: usize,
+}
+
+impl<T> Default for Bvh<T> {
+    fn default() -> Self {
+        Self::build(Vec::new())
+    }
+}
+
+impl<T> Bvh<T> {
+    /// Builds the hierarchy by recursively splitting the items at the median of the longest
+    /// axis of their centroids' bounds. The keys of the items must be unique.
+    pub fn build(items: Vec<BvhItem<T>>) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            node_parents: Vec::new(),
+            item_leaves: vec![0; items.len()],
+            slots: items.iter().enumerate().map(|(slot, item)| (item.key, slot)).collect(),
+            items,
+            built_cost: 0.0,
+            churn: 0,
+        };
+
+        bvh.rebuild();
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, key: Index) -> Option<&BvhItem<T>> {
+        self.slots.get(&key).map(|slot| &self.items[*slot])
+    }
+
+    /// Rebuilds the hierarchy from scratch.
+    pub fn rebuild(&mut self) {
+        let mut order: Vec<usize> = (0..self.items.len()).collect();
+
+        self.nodes.clear();
+        self.node_parents.clear();
+
+        if !order.is_empty() {
+            self.build_node(&mut order[..], None);
+        }
+
+        self.built_cost = self.cost();
+        self.churn = 0;
+    }
+
+    /// Rebuilds the hierarchy, if it has degraded too much since it was last built.
+    /// Returns whether it was rebuilt.
+    pub fn rebuild_if_degraded(&mut self) -> bool {
+        let degraded = self.churn as f32 > self.items.len() as f32 * MAX_CHURN
+            || self.cost() > self.built_cost * MAX_COST_GROWTH;
+
+        if degraded {
+            self.rebuild();
+        }
+
+        degraded
+    }
+
+    /// The total surface area of the nodes, which is proportional to the expected number of
+    /// nodes a query visits.
+    fn cost(&self) -> f32 {
+        self.nodes.iter().map(|node| node.bounds().surface_area()).sum()
+    }
+
+    fn build_node(&mut self, order: &mut [usize], parent: Option<usize>) -> usize {
+        let bounds = order.iter().fold(Aabb::EMPTY, |bounds, slot| bounds.union(&self.items[*slot].bounds));
+        let index = self.nodes.len();
+
+        self.node_parents.push(parent);
+
+        if order.len() <= LEAF_SIZE {
+            for slot in order.iter() {
+                self.item_leaves[*slot] = index;
+            }
+
+            self.nodes.push(BvhNode::Leaf { bounds, items: order.to_vec() });
+            return index;
+        }
+
+        let items = &self.items;
+        let centroid_bounds = order.iter()
+            .fold(Aabb::EMPTY, |centroid_bounds, slot| centroid_bounds.union_point(items[*slot].bounds.centroid()));
+        let axis = (0..3)
+            .max_by(|a, b| {
+                let extent = |axis: usize| centroid_bounds.max[axis] - centroid_bounds.min[axis];
+                extent(*a).partial_cmp(&extent(*b)).unwrap_or(Ordering::Equal)
+            })
+            .unwrap();
+
+        order.sort_unstable_by(|a, b| {
+            items[*a].bounds.centroid()[axis].partial_cmp(&items[*b].bounds.centroid()[axis]).unwrap_or(Ordering::Equal)
+        });
+
+        let (left_order, right_order) = order.split_at_mut(order.len() / 2);
+
+        // Reserve the node, so that it precedes its children.
+        self.nodes.push(BvhNode::Leaf { bounds, items: Vec::new() });
+
+        let left = self.build_node(left_order, Some(index));
+        let right = self.build_node(right_order, Some(index));
+
+        self.nodes[index] = BvhNode::Branch { bounds, children: [left, right] };
+
+        index
+    }
+
+    /// Updates the item with the key, or inserts it into the leaf whose bounds grow the least,
+    /// and refits the bounds of the leaf and its ancestors.
+    pub fn insert_or_update(&mut self, key: Index, bounds: Aabb, value: T) {
+        if let Some(slot) = self.slots.get(&key).cloned() {
+            self.items[slot].bounds = bounds;
+            self.items[slot].value = value;
+            self.refit(self.item_leaves[slot]);
+            return;
+        }
+
+        let slot = self.items.len();
+
+        self.items.push(BvhItem { key, bounds, value });
+        self.slots.insert(key, slot);
+        self.churn += 1;
+
+        if self.nodes.is_empty() {
+            self.item_leaves.push(0);
+            self.node_parents.push(None);
+            self.nodes.push(BvhNode::Leaf { bounds, items: vec![slot] });
+            return;
+        }
+
+        let mut index = 0;
+
+        while let BvhNode::Branch { children, .. } = &self.nodes[index] {
+            let growth = |child: usize| {
+                let child_bounds = self.nodes[child].bounds();
+
+                child_bounds.union(&bounds).surface_area() - child_bounds.surface_area()
+            };
+
+            index = if growth(children[0]) <= growth(children[1]) { children[0] } else { children[1] };
+        }
+
+        self.leaf_items_mut(index).push(slot);
+        self.item_leaves.push(index);
+        self.refit(index);
+    }
+
+    /// Removes the item with the key, if any, and refits the bounds of its leaf and the
+    /// leaf's ancestors.
+    pub fn remove(&mut self, key: Index) -> Option<T> {
+        let slot = self.slots.remove(&key)?;
+        let leaf = self.item_leaves[slot];
+
+        self.leaf_items_mut(leaf).retain(|item| *item != slot);
+        self.churn += 1;
+
+        // Move the last item into the vacated slot.
+        let last = self.items.len() - 1;
+        let item = self.items.swap_remove(slot);
+
+        self.item_leaves.swap_remove(slot);
+
+        if slot != last {
+            let moved_leaf = self.item_leaves[slot];
+
+            for item in self.leaf_items_mut(moved_leaf) {
+                if *item == last {
+                    *item = slot;
+                }
+            }
+
+            self.slots.insert(self.items[slot].key, slot);
+        }
+
+        if self.items.is_empty() {
+            self.rebuild();
+        } else {
+            self.refit(leaf);
+        }
+
+        Some(item.value)
+    }
+
+    fn leaf_items_mut(&mut self, index: usize) -> &mut Vec<usize> {
+        match &mut self.nodes[index] {
+            BvhNode::Leaf { items, .. } => items,
+            BvhNode::Branch { .. } => unreachable!("Items are only stored in leaves."),
+        }
+    }
+
+    /// Recomputes the bounds of the node and its ancestors, up to the first whose bounds
+    /// remain the same.
+    fn refit(&mut self, mut index: usize) {
+        loop {
+            let bounds = match &self.nodes[index] {
+                BvhNode::Leaf { items, .. } => items.iter()
+                    .fold(Aabb::EMPTY, |bounds, slot| bounds.union(&self.items[*slot].bounds)),
+                BvhNode::Branch { children, .. } => self.nodes[children[0]].bounds()
+                    .union(self.nodes[children[1]].bounds()),
+            };
+
+            match &mut self.nodes[index] {
+                BvhNode::Branch { bounds: node_bounds, .. } | BvhNode::Leaf { bounds: node_bounds, .. } => {
+                    if *node_bounds == bounds {
+                        return;
+                    }
+
+                    *node_bounds = bounds;
+                },
+            }
+
+            match self.node_parents[index] {
+                Some(parent) => index = parent,
+                None => return,
+            }
+        }
+    }
+
+    /// Returns the items whose bounds the ray hits, ordered by the distance at which the ray
+    /// enters their bounds.
+    pub fn ray_candidates(&self, origin: [f32; 3], direction: [f32; 3]) -> Vec<(f32, &BvhItem<T>)> {
+        let direction_inverse = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+        let mut candidates = Vec::new();
+        let mut stack = Vec::new();
+
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+
+            if node.bounds().intersect_ray(origin, direction_inverse).is_none() {
+                continue;
+            }
+
+            match node {
+                BvhNode::Branch { children, .. } => stack.extend(children.iter()),
+                BvhNode::Leaf { items, .. } => {
+                    for item in items.iter().map(|slot| &self.items[*slot]) {
+                        if let Some(distance) = item.bounds.intersect_ray(origin, direction_inverse) {
+                            candidates.push((distance, item));
+                        }
+                    }
+                },
+            }
+        }
+
+        candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        candidates
+    }
+
+    /// Returns the items whose bounds overlap the box.
+    pub fn overlapping(&self, bounds: &Aabb) -> Vec<&BvhItem<T>> {
+        let mut result = Vec::new();
+        let mut stack = Vec::new();
+
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+
+            if !node.bounds().intersects(bounds) {
+                continue;
+            }
+
+            match node {
+                BvhNode::Branch { children, .. } => stack.extend(children.iter()),
+                BvhNode::Leaf { items, .. } => {
+                    result.extend(items.iter()
+                        .map(|slot| &self.items[*slot])
+                        .filter(|item| item.bounds.intersects(bounds)));
+                },
+            }
+        }
+
+        result
+    }
+}
+
+/// A model in the spatial index, with the data needed to test it precisely.
+pub struct SpatialItem {
+    pub entity: Entity,
+    pub matrix: Mat4,
+    pub geometry: Arc<ModelGeometry>,
+}
+
+/// The spatial index of all entities with a model, including hidden ones, so that queries
+/// may decide whether to skip them using `ResourceRenderData::hidden`.
+#[derive(Default)]
+pub struct ResourceSpatialIndex {
+    pub bvh: Bvh<SpatialItem>,
+}
+
+/// Updates `ResourceSpatialIndex` with the entities whose absolute transforms or models have
+/// changed, keyed by their ids.
+#[derive(Default)]
+pub struct SystemSpatialIndex {
+    transform_reader: Option<ReaderId<ComponentEvent>>,
+    model_reader: Option<ReaderId<ComponentEvent>>,
+    /// The ids of the entities to update, kept to avoid reallocating it every frame.
+    dirty: BitSet,
+}
+
+impl<'a> System<'a> for SystemSpatialIndex {
+    type SystemData = (
+        Write<'a, ResourceSpatialIndex>,
+        Entities<'a>,
+        ReadStorage<'a, ComponentTransformAbsolute>,
+        ReadStorage<'a, ComponentModel>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+
+        self.transform_reader = Some(world.write_storage::<ComponentTransformAbsolute>().register_reader());
+        self.model_reader = Some(world.write_storage::<ComponentModel>().register_reader());
+    }
+
+    fn run(&mut self, (mut spatial_index, entities, transform, model): Self::SystemData) {
+        self.dirty.clear();
+
+        let events = transform.channel()
+            .read(self.transform_reader.as_mut().expect("The system has not been set up."))
+            .chain(model.channel().read(self.model_reader.as_mut().expect("The system has not been set up.")));
+
+        for event in events {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) | ComponentEvent::Removed(id) => {
+                    self.dirty.add(*id);
+                },
+            }
+        }
+
+        let bvh = &mut spatial_index.bvh;
+        let mut changed = false;
+
+        for id in (&self.dirty).join() {
+            let entity = entities.entity(id);
+
+            match (transform.get(entity), model.get(entity)) {
+                (Some(transform), Some(model)) if entities.is_alive(entity) => {
+                    bvh.insert_or_update(id, model.geometry.bounds.transformed(&transform.matrix), SpatialItem {
+                        entity,
+                        matrix: transform.matrix.clone(),
+                        geometry: model.geometry.clone(),
+                    });
+                },
+                _ => {
+                    bvh.remove(id);
+                },
+            }
+
+            changed = true;
+        }
+
+        if changed {
+            bvh.rebuild_if_degraded();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> Aabb {
+        Aabb { min, max }
+    }
+
+    fn unit_box_at(x: f32) -> Aabb {
+        aabb([x, 0.0, 0.0], [x + 1.0, 1.0, 1.0])
+    }
+
+    fn row(count: usize) -> Bvh<usize> {
+        Bvh::build((0..count)
+            .map(|index| BvhItem { key: index as Index, bounds: unit_box_at(index as f32 * 2.0), value: index })
+            .collect())
+    }
+
+    fn ray_values(bvh: &Bvh<usize>, origin: [f32; 3], direction: [f32; 3]) -> Vec<usize> {
+        bvh.ray_candidates(origin, direction).into_iter()
+            .map(|(_, item)| item.value)
+            .collect()
+    }
+
+    fn overlapping_values(bvh: &Bvh<usize>, bounds: &Aabb) -> Vec<usize> {
+        let mut values: Vec<usize> = bvh.overlapping(bounds).into_iter()
+            .map(|item| item.value)
+            .collect();
+
+        values.sort();
+        values
+    }
+
+    /// Checks that each node's bounds contain those of its children and items.
+    fn assert_consistent(bvh: &Bvh<usize>) {
+        let contains = |outer: &Aabb, inner: &Aabb| inner.is_empty() || outer.union(inner) == *outer;
+
+        for (index, node) in bvh.nodes.iter().enumerate() {
+            match node {
+                BvhNode::Branch { bounds, children } => {
+                    for child in children {
+                        assert!(contains(bounds, bvh.nodes[*child].bounds()));
+                        assert_eq!(bvh.node_parents[*child], Some(index));
+                    }
+                },
+                BvhNode::Leaf { bounds, items } => {
+                    for slot in items {
+                        assert!(contains(bounds, &bvh.items[*slot].bounds));
+                        assert_eq!(bvh.item_leaves[*slot], index);
+                    }
+                },
+            }
+        }
+
+        for (slot, item) in bvh.items.iter().enumerate() {
+            assert_eq!(bvh.slots[&item.key], slot);
+        }
+    }
+
+    #[test]
+    fn aabb_union_and_intersection() {
+        let a = aabb([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+        let b = aabb([2.0, -1.0, 0.5], [3.0, 0.5, 2.0]);
+
+        assert_eq!(a.union(&b), aabb([0.0, -1.0, 0.0], [3.0, 1.0, 2.0]));
+        assert_eq!(a.union(&Aabb::EMPTY), a);
+        assert_eq!(a.union_point([0.5, 2.0, 0.5]), aabb([0.0, 0.0, 0.0], [1.0, 2.0, 1.0]));
+        assert_eq!(b.centroid(), [2.5, -0.25, 1.25]);
+        assert_eq!(a.surface_area(), 6.0);
+        assert_eq!(Aabb::EMPTY.surface_area(), 0.0);
+        assert!(!a.intersects(&b));
+        assert!(a.intersects(&aabb([1.0, 1.0, 1.0], [2.0, 2.0, 2.0])));
+        assert!(!a.intersects(&Aabb::EMPTY));
+    }
+
+    fn assert_point_close(actual: [f32; 3], expected: [f32; 3]) {
+        for axis in 0..3 {
+            assert!((actual[axis] - expected[axis]).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn affine_inverse_undoes_the_transform() {
+        // A rotation by a quarter turn about z, a scale by 2 along x and a translation.
+        let affine = Affine {
+            axes: [[0.0, 2.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+            translation: [1.0, 2.0, 3.0],
+        };
+        let inverse = affine.inverse().unwrap();
+        let point = [0.5, -4.0, 7.0];
+
+        assert_point_close(affine.transform_point([1.0, 0.0, 0.0]), [1.0, 4.0, 3.0]);
+        assert_point_close(inverse.transform_point(affine.transform_point(point)), point);
+        assert_point_close(affine.transform_point(inverse.transform_point(point)), point);
+        assert_eq!(
+            affine.transform_bounds(&aabb([0.0, 0.0, 0.0], [1.0, 1.0, 1.0])),
+            aabb([0.0, 2.0, 3.0], [1.0, 4.0, 4.0]),
+        );
+
+        let flat = Affine {
+            axes: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 0.0]],
+            translation: [0.0; 3],
+        };
+
+        assert_eq!(flat.inverse(), None);
+    }
+
+    #[test]
+    fn aabb_ray_intersection() {
+        let a = aabb([1.0, -1.0, -1.0], [2.0, 1.0, 1.0]);
+        let inverse = |direction: [f32; 3]| [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+
+        assert_eq!(a.intersect_ray([0.0, 0.0, 0.0], inverse([1.0, 0.0, 0.0])), Some(1.0));
+        assert_eq!(a.intersect_ray([1.5, 0.0, 0.0], inverse([1.0, 0.0, 0.0])), Some(0.0));
+        assert_eq!(a.intersect_ray([0.0, 0.0, 0.0], inverse([-1.0, 0.0, 0.0])), None);
+        assert_eq!(a.intersect_ray([0.0, 2.0, 0.0], inverse([1.0, 0.0, 0.0])), None);
+        assert_eq!(Aabb::EMPTY.intersect_ray([0.0, 0.0, 0.0], inverse([1.0, 0.0, 0.0])), None);
+    }
+
+    #[test]
+    fn model_geometry_bounds() {
+        let geometry = ModelGeometry::new(vec![
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 2.0, 0.0]],
+            [[0.0, 0.0, -3.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+        ], vec![TriangleSource::default(); 2]);
+
+        assert_eq!(geometry.bounds, aabb([0.0, 0.0, -3.0], [1.0, 2.0, 1.0]));
+        assert!(ModelGeometry::new(Vec::new(), Vec::new()).bounds.is_empty());
+    }
+
+    #[test]
+    fn model_geometry_ray_hits() {
+        let texcoords = Some([[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]);
+        let source = |triangle_index| TriangleSource { mesh_index: 1, primitive_index: 2, triangle_index, texcoords };
+        // Two parallel right triangles facing +z, at z = 0 and z = -2.
+        let geometry = ModelGeometry::new(vec![
+            [[0.0, 0.0, -2.0], [4.0, 0.0, -2.0], [0.0, 4.0, -2.0]],
+            [[0.0, 0.0, 0.0], [4.0, 0.0, 0.0], [0.0, 4.0, 0.0]],
+        ], vec![source(0), source(1)]);
+
+        let hit = geometry.intersect_ray([1.0, 2.0, 5.0], [0.0, 0.0, -2.0]).unwrap();
+
+        assert_eq!(hit.triangle, 1);
+        assert_eq!(hit.distance, 2.5);
+        assert_eq!(hit.barycentric, [0.25, 0.5]);
+        assert_eq!(geometry.texcoord(&hit), Some([0.25, 0.5]));
+
+        // Triangles are hit from behind as well.
+        assert_eq!(geometry.intersect_ray([1.0, 1.0, -5.0], [0.0, 0.0, 1.0]).unwrap().triangle, 0);
+        assert_eq!(geometry.intersect_ray([3.0, 3.0, 5.0], [0.0, 0.0, -1.0]), None);
+        assert_eq!(geometry.intersect_ray([1.0, 1.0, 5.0], [0.0, 0.0, 1.0]), None);
+        assert_eq!(geometry.intersect_ray([1.0, 1.0, 5.0], [1.0, 0.0, 0.0]), None);
+
+        let untextured = ModelGeometry::new(geometry.triangles.clone(), vec![TriangleSource::default(); 2]);
+
+        assert_eq!(untextured.texcoord(&hit), None);
+
+        // Mirrored along x and scaled, the normals still face the ray.
+        let affine = Affine {
+            axes: [[-2.0, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 2.0]],
+            translation: [0.0; 3],
+        };
+
+        assert_eq!(geometry.facing_normal(1, &affine, [0.0, 0.0, -1.0]), [0.0, 0.0, 1.0]);
+        assert_eq!(geometry.facing_normal(1, &affine, [0.0, 0.0, 1.0]), [0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn build_contains_every_item() {
+        for count in &[0, 1, LEAF_SIZE, LEAF_SIZE + 1, 37] {
+            let bvh = row(*count);
+
+            assert_eq!(bvh.len(), *count);
+            assert_consistent(&bvh);
+            assert_eq!(overlapping_values(&bvh, &aabb([-1.0; 3], [1000.0; 3])), (0..*count).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn traversal_finds_the_hit_items_in_order() {
+        let bvh = row(20);
+
+        assert_eq!(ray_values(&bvh, [-1.0, 0.5, 0.5], [1.0, 0.0, 0.0]), (0..20).collect::<Vec<_>>());
+        assert_eq!(ray_values(&bvh, [100.0, 0.5, 0.5], [-1.0, 0.0, 0.0]), (0..20).rev().collect::<Vec<_>>());
+        assert_eq!(ray_values(&bvh, [10.5, 5.0, 0.5], [0.0, -1.0, 0.0]), vec![5]);
+        assert_eq!(ray_values(&bvh, [11.5, 5.0, 0.5], [0.0, -1.0, 0.0]), Vec::<usize>::new());
+        assert_eq!(overlapping_values(&bvh, &aabb([3.5, 0.0, 0.0], [8.5, 1.0, 1.0])), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn refit_follows_updates() {
+        let mut bvh = row(20);
+
+        bvh.insert_or_update(3, unit_box_at(100.0), 3);
+
+        assert_consistent(&bvh);
+        assert_eq!(overlapping_values(&bvh, &aabb([99.0, 0.0, 0.0], [102.0, 1.0, 1.0])), vec![3]);
+        assert!(overlapping_values(&bvh, &unit_box_at(6.0)).is_empty());
+        assert_eq!(bvh.get(3).map(|item| item.bounds), Some(unit_box_at(100.0)));
+    }
+
+    #[test]
+    fn refit_follows_insertions_and_removals() {
+        let mut bvh = row(20);
+
+        bvh.insert_or_update(20, unit_box_at(-10.0), 20);
+        assert_eq!(bvh.remove(5), Some(5));
+        assert_eq!(bvh.remove(5), None);
+        assert_eq!(bvh.remove(0), Some(0));
+
+        assert_consistent(&bvh);
+        assert_eq!(bvh.len(), 19);
+        assert_eq!(ray_values(&bvh, [-20.0, 0.5, 0.5], [1.0, 0.0, 0.0])[..3], [20, 1, 2]);
+        assert!(overlapping_values(&bvh, &unit_box_at(10.0)).is_empty());
+
+        for key in 1..21 {
+            bvh.remove(key);
+        }
+
+        assert!(bvh.is_empty());
+        assert!(ray_values(&bvh, [-20.0, 0.5, 0.5], [1.0, 0.0, 0.0]).is_empty());
+    }
+
+    #[test]
+    fn degraded_hierarchies_are_rebuilt() {
+        let mut bvh = row(20);
+
+        assert!(!bvh.rebuild_if_degraded());
+
+        // Scattering items across the row makes the nodes overlap.
+        for key in 0..10 {
+            bvh.insert_or_update(key, unit_box_at(if key % 2 == 0 { -200.0 } else { 200.0 }), key as usize);
+        }
+
+        assert!(bvh.rebuild_if_degraded());
+        assert_consistent(&bvh);
+        assert!(!bvh.rebuild_if_degraded());
+
+        for key in 20..28 {
+            bvh.insert_or_update(key, unit_box_at(key as f32 * 2.0), key as usize);
+        }
+
+        assert!(bvh.rebuild_if_degraded());
+        assert_consistent(&bvh);
+        assert_eq!(overlapping_values(&bvh, &aabb([-1000.0; 3], [1000.0; 3])).len(), 28);
+    }
+}
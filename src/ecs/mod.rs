@@ -2,13 +2,20 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::time::Duration;
 use std::sync::Arc;
-use ammolite_math::Mat4;
+use std::collections::{HashMap, HashSet};
+use ammolite_math::{AffineTransformation, Rotation3, Mat4, Vec3, Quaternion};
 use ammolite::model::Model;
 use ammolite::WorldSpaceModel;
 use ammolite::camera::Camera;
 use specs::{join, world::{Index, EntitiesRes}};
 use specs::prelude::*;
-use specs_hierarchy::{Hierarchy, HierarchySystem};
+use specs::storage::ComponentEvent;
+use specs_hierarchy::{Hierarchy, HierarchyEvent, HierarchySystem};
+use crate::ecs::bvh::ModelGeometry;
+
+pub mod animation;
+pub mod bvh;
+pub mod shape;
 
 pub struct ComponentParent {
     pub entity: Entity,
@@ -33,21 +40,236 @@ impl Component for ComponentTransformRelative {
     type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
 
+/// Specifies the relative transformation as separate parts, from which
+/// `SystemTransformCompose` builds `ComponentTransformRelative`.
+#[derive(Clone)]
+pub struct ComponentTransformDecomposed {
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+}
+
+impl ComponentTransformDecomposed {
+    /// Scales, rotates and then translates, with the matrices composed in the same order as
+    /// in `SystemTransformInheritance`.
+    pub fn to_matrix(&self) -> Mat4 {
+        Mat4::scale(self.scale.clone())
+            * self.rotation.clone().to_homogeneous()
+            * Mat4::translation(self.translation.clone())
+    }
+
+    /// Decomposes an affine transformation into the parts `to_matrix` composes.
+    /// Shear cannot be represented and is dropped; a reflection becomes a negative scale
+    /// along the X axis.
+    pub fn from_matrix(matrix: &Mat4) -> Self {
+        use ammolite_math::*;
+
+        let transform = |point: [f32; 3]| (matrix * Vec3(point).into_homogeneous_position()).into_projected().0;
+        let origin = transform([0.0; 3]);
+        let mut axes = [[0.0; 3]; 3];
+        let mut scale = [0.0; 3];
+
+        for axis in 0..3 {
+            let mut unit = [0.0; 3];
+
+            unit[axis] = 1.0;
+            axes[axis] = vector_sub(transform(unit), origin);
+            scale[axis] = vector_length(axes[axis]);
+        }
+
+        if vector_dot(vector_cross(axes[0], axes[1]), axes[2]) < 0.0 {
+            scale[0] = -scale[0];
+            axes[0] = vector_scale(axes[0], -1.0);
+        }
+
+        Self {
+            translation: Vec3(origin),
+            rotation: rotation_from_axes(axes),
+            scale: Vec3(scale),
+        }
+    }
+}
+
+fn vector_sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn vector_scale(a: [f32; 3], factor: f32) -> [f32; 3] {
+    [a[0] * factor, a[1] * factor, a[2] * factor]
+}
+
+fn vector_dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn vector_cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn vector_length(a: [f32; 3]) -> f32 {
+    vector_dot(a, a).sqrt()
+}
+
+fn vector_normalize(a: [f32; 3]) -> Option<[f32; 3]> {
+    let length = vector_length(a);
+
+    if length > std::f32::EPSILON {
+        Some(vector_scale(a, 1.0 / length))
+    } else {
+        None
+    }
+}
+
+/// The rotation mapping the coordinate axes onto the directions of `axes`, which are
+/// orthonormalized first. Collapsed axes are rebuilt from the others, where possible.
+fn rotation_from_axes(axes: [[f32; 3]; 3]) -> Quaternion {
+    let x = match vector_normalize(axes[0]).or_else(|| vector_normalize(vector_cross(axes[1], axes[2]))) {
+        Some(x) => x,
+        None => return Quaternion([0.0, 0.0, 0.0, 1.0]),
+    };
+    let y = vector_normalize(vector_sub(axes[1], vector_scale(x, vector_dot(axes[1], x))))
+        .or_else(|| vector_normalize(vector_cross(axes[2], x)))
+        .or_else(|| {
+            // Any direction perpendicular to X will do.
+            let helper = if x[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+            vector_normalize(vector_cross(x, helper))
+        })
+        .expect("A normalized vector has a perpendicular direction.");
+    let z = vector_cross(x, y);
+    // The rotation matrix has the axes as its columns, `m[row][column]`.
+    let m = [
+        [x[0], y[0], z[0]],
+        [x[1], y[1], z[1]],
+        [x[2], y[2], z[2]],
+    ];
+    let trace = m[0][0] + m[1][1] + m[2][2];
+
+    // Shepperd's method, which divides by the largest of the candidate denominators.
+    let [qx, qy, qz, qw] = if trace > 0.0 {
+        let s = (trace + 1.0).sqrt() * 2.0;
+        [(m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s, 0.25 * s]
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+        [0.25 * s, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s, (m[2][1] - m[1][2]) / s]
+    } else if m[1][1] > m[2][2] {
+        let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+        [(m[0][1] + m[1][0]) / s, 0.25 * s, (m[1][2] + m[2][1]) / s, (m[0][2] - m[2][0]) / s]
+    } else {
+        let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+        [(m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, 0.25 * s, (m[1][0] - m[0][1]) / s]
+    };
+
+    Quaternion([qx, qy, qz, qw])
+}
+
+impl Component for ComponentTransformDecomposed {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
 #[derive(Default)]
 pub struct ComponentTransformAbsolute {
     pub matrix: Mat4,
 }
 
 impl Component for ComponentTransformAbsolute {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+/// Hides the entity and all of its descendants, if `visible` is unset.
+/// Entities without this component are visible.
+pub struct ComponentVisible {
+    pub visible: bool,
+}
+
+impl Component for ComponentVisible {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+/// The layers the entity belongs to, as a bit mask, by which spatial queries may be filtered.
+/// Entities without this component belong to `ComponentLayers::DEFAULT`.
+pub struct ComponentLayers {
+    pub mask: u32,
+}
+
+impl ComponentLayers {
+    pub const DEFAULT: u32 = 1;
+}
+
+impl Component for ComponentLayers {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A name for debugging and for other mapps to find the entity by. Names need not be unique.
+pub struct ComponentName {
+    pub name: String,
+}
+
+impl Component for ComponentName {
+    type Storage = DenseVecStorage<Self>;
+}
+
+pub struct ComponentTags {
+    pub tags: HashSet<String>,
+}
+
+impl Component for ComponentTags {
+    type Storage = DenseVecStorage<Self>;
 }
 
 pub struct ComponentModel {
     pub model: Arc<Model>,
+    pub geometry: Arc<ModelGeometry>,
 }
 
 impl Component for ComponentModel {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LightKind {
+    /// Shines along the entity's forward direction, from infinitely far away.
+    Directional,
+    /// Shines in all directions from the entity's position.
+    Point,
+    /// Shines in a cone along the entity's forward direction. The angles are in radians.
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+/// A light source, positioned and oriented by the entity's absolute transform.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComponentLight {
+    pub kind: LightKind,
+    /// Linear RGB.
+    pub color: Vec3,
+    pub intensity: f32,
+    /// The distance at which the light no longer has an effect, or `None` for no limit.
+    /// Ignored by directional lights.
+    pub range: Option<f32>,
+}
+
+impl ComponentLight {
+    /// Checks that the intensity is non-negative, the range positive and that the inner cone
+    /// of a spot light is not wider than its outer cone.
+    pub fn is_valid(&self) -> bool {
+        let cone_valid = match self.kind {
+            LightKind::Directional | LightKind::Point => true,
+            LightKind::Spot { inner_cone_angle, outer_cone_angle } =>
+                inner_cone_angle >= 0.0 && inner_cone_angle <= outer_cone_angle,
+        };
+
+        self.intensity >= 0.0 && self.range.map(|range| range > 0.0).unwrap_or(true) && cone_valid
+    }
+}
+
+impl Component for ComponentLight {
+    type Storage = DenseVecStorage<Self>;
 }
 
 pub struct ResourceSceneRoot(pub Entity);
@@ -60,13 +282,178 @@ pub struct ResourceTimeElapsedDelta(pub Duration);
 
 #[derive(Default)]
 pub struct ResourceRenderData {
-    pub world_space_models: Vec<(Index, Mat4, Arc<Model>)>,
+    pub world_space_models: Vec<(Entity, Mat4, Arc<Model>)>,
+    /// The visible light sources, along with their absolute transforms.
+    pub lights: Vec<(Entity, Mat4, ComponentLight)>,
+    /// Hidden entities, including the descendants of hidden entities.
+    pub hidden: BitSet,
+}
+
+/// Collects `root` and all of its descendants, each parent preceding its children.
+///
+/// Unlike `Hierarchy<ComponentParent>`, which is only maintained during dispatch, this reads
+/// `ComponentParent` directly, so it includes entities parented since the last frame.
+pub fn collect_subtree(world: &World, root: Entity) -> Vec<Entity> {
+    let entities = world.entities();
+    let parents = world.read_storage::<ComponentParent>();
+    let mut children: HashMap<Entity, Vec<Entity>> = HashMap::new();
+
+    for (entity, parent) in (&*entities, &parents).join() {
+        children.entry(parent.entity).or_default().push(entity);
+    }
+
+    let mut visited = HashSet::new();
+    let mut subtree = vec![root];
+    let mut index = 0;
+
+    visited.insert(root);
+
+    while index < subtree.len() {
+        if let Some(children) = children.get(&subtree[index]) {
+            for child in children {
+                if visited.insert(*child) {
+                    subtree.push(*child);
+                }
+            }
+        }
+
+        index += 1;
+    }
+
+    subtree
 }
 
-pub struct SystemTransformInheritance;
+/// Checks whether `entity` is `root` or one of its descendants, by walking up the parents of
+/// `entity`, which is cheaper than collecting the subtree to test a few entities.
+pub fn is_in_subtree(parents: &ReadStorage<ComponentParent>, entity: Entity, root: Entity) -> bool {
+    let mut current = entity;
+    // Follows the parents at half the pace, to detect cycles without allocating.
+    let mut trailing = entity;
+    let mut steps = 0usize;
+
+    loop {
+        if current == root {
+            return true;
+        }
+
+        current = match parents.get(current) {
+            Some(parent) => parent.entity,
+            None => return false,
+        };
+        steps += 1;
+
+        if steps % 2 == 0 {
+            trailing = parents.get(trailing)
+                .expect("The trailing entity is an ancestor of the current one.")
+                .entity;
+        }
+
+        if current == trailing {
+            return false;
+        }
+    }
+}
+
+/// Deletes `entity` from the world and returns the deleted entities.
+///
+/// If `recursive` is set, all of the entity's descendants are deleted along with it.
+/// Otherwise, its children are reattached to its parent, or detached from the scene,
+/// if it has none.
+pub fn delete_entity(world: &mut World, entity: Entity, recursive: bool) -> Vec<Entity> {
+    let deleted = if recursive {
+        collect_subtree(world, entity)
+    } else {
+        let entities = world.entities();
+        let mut parents = world.write_storage::<ComponentParent>();
+        let grandparent = parents.get(entity).map(|parent| parent.entity);
+        let children: Vec<Entity> = (&*entities, &parents).join()
+            .filter(|(_, parent)| parent.entity == entity)
+            .map(|(child, _)| child)
+            .collect();
+
+        for child in children {
+            if let Some(grandparent) = grandparent {
+                parents.insert(child, ComponentParent {
+                    entity: grandparent,
+                }).expect("An error occurred while inserting a component into storage.");
+            } else {
+                parents.remove(child);
+            }
+        }
+
+        vec![entity]
+    };
+
+    // Also removes the components, so that the entities are not rendered on this frame.
+    world.delete_entities(&deleted[..])
+        .expect("Could not delete the entities.");
+
+    deleted
+}
+
+/// Builds `ComponentTransformRelative` from the modified `ComponentTransformDecomposed` components.
+#[derive(Default)]
+pub struct SystemTransformCompose {
+    decomposed_reader: Option<ReaderId<ComponentEvent>>,
+    modified: BitSet,
+}
+
+impl<'a> System<'a> for SystemTransformCompose {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, ComponentTransformDecomposed>,
+        WriteStorage<'a, ComponentTransformRelative>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+
+        self.decomposed_reader = Some(world.write_storage::<ComponentTransformDecomposed>().register_reader());
+    }
+
+    fn run(&mut self, (entities, transform_decomposed, mut transform_rel): Self::SystemData) {
+        self.modified.clear();
+
+        let events = transform_decomposed.channel()
+            .read(self.decomposed_reader.as_mut().expect("The system has not been set up."));
+
+        // Removals are left to whoever removed the component, as it may have been replaced
+        // by a raw matrix.
+        for event in events {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    self.modified.add(*id);
+                },
+                ComponentEvent::Removed(_) => (),
+            }
+        }
+
+        for (entity, transform_decomposed, _) in (&entities, &transform_decomposed, &self.modified).join() {
+            transform_rel.insert(entity, ComponentTransformRelative {
+                matrix: transform_decomposed.to_matrix(),
+            }).expect("An error occurred while inserting a component into storage.");
+        }
+    }
+}
+
+/// Keeps `ComponentTransformAbsolute` up to date.
+///
+/// Only the subtrees whose `ComponentParent` or `ComponentTransformRelative` components
+/// changed since the previous run are recomputed, so static parts of the scene are free.
+#[derive(Default)]
+pub struct SystemTransformInheritance {
+    parent_reader: Option<ReaderId<ComponentEvent>>,
+    transform_reader: Option<ReaderId<ComponentEvent>>,
+    hierarchy_reader: Option<ReaderId<HierarchyEvent>>,
+    /// Entities whose absolute transform has to be recomputed.
+    modified: BitSet,
+    /// Ancestors of modified entities, through which the traversal has to descend.
+    ancestors: BitSet,
+}
 
 impl<'a> System<'a> for SystemTransformInheritance {
     type SystemData = (
+        Entities<'a>,
         ReadExpect<'a, Hierarchy<ComponentParent>>,
         ReadExpect<'a, ResourceSceneRoot>,
         ReadStorage<'a, ComponentParent>,
@@ -74,42 +461,212 @@ impl<'a> System<'a> for SystemTransformInheritance {
         WriteStorage<'a, ComponentTransformAbsolute>,
     );
 
-    fn run(&mut self, (hierarchy, scene_root, parent, transform_rel, mut transform_abs): Self::SystemData) {
-        // Note: This does not include entities that are parents.
-        for entity in hierarchy.all_children_iter(scene_root.0) {
-            // FIXME: Only update when rel or abs were updated
-            if let Some(transform_rel) = transform_rel.get(entity) {
-                let mut matrix_abs_new = transform_rel.matrix.clone();
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+
+        self.parent_reader = Some(world.write_storage::<ComponentParent>().register_reader());
+        self.transform_reader = Some(world.write_storage::<ComponentTransformRelative>().register_reader());
+        self.hierarchy_reader = Some(world.fetch_mut::<Hierarchy<ComponentParent>>().track());
+    }
+
+    fn run(&mut self, (entities, hierarchy, scene_root, parent, transform_rel, mut transform_abs): Self::SystemData) {
+        self.modified.clear();
+        self.ancestors.clear();
+
+        let parent_events = parent.channel()
+            .read(self.parent_reader.as_mut().expect("The system has not been set up."));
+        let transform_events = transform_rel.channel()
+            .read(self.transform_reader.as_mut().expect("The system has not been set up."));
+
+        for event in parent_events.chain(transform_events) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) | ComponentEvent::Removed(id) => {
+                    self.modified.add(*id);
+                },
+            }
+        }
+
+        for event in hierarchy.changed().read(self.hierarchy_reader.as_mut().expect("The system has not been set up.")) {
+            if let HierarchyEvent::Modified(entity) = event {
+                self.modified.add(entity.id());
+            }
+        }
+
+        // Mark the ancestors of modified entities, so that unmodified subtrees are skipped.
+        for id in (&self.modified).join() {
+            let mut entity = entities.entity(id);
+
+            if !entities.is_alive(entity) {
+                continue;
+            }
 
-                if let Some(parent) = parent.get(entity) {
-                    if let Some(parent_transform_abs) = transform_abs.get(parent.entity) {
-                        matrix_abs_new = matrix_abs_new * &parent_transform_abs.matrix;
+            while let Some(parent) = parent.get(entity) {
+                // Stop at an ancestor that has already been marked.
+                if self.ancestors.add(parent.entity.id()) {
+                    break;
+                }
+
+                entity = parent.entity;
+            }
+        }
+
+        // An explicit depth-first traversal, so that parents are always updated before
+        // their children, regardless of the depth of the hierarchy.
+        let mut stack = vec![(scene_root.0, false)];
+
+        while let Some((entity, parent_modified)) = stack.pop() {
+            for &child in hierarchy.children(entity) {
+                let modified = parent_modified || self.modified.contains(child.id());
+
+                if modified {
+                    if let Some(transform_rel) = transform_rel.get(child) {
+                        let mut matrix_abs_new = transform_rel.matrix.clone();
+
+                        if let Some(parent_transform_abs) = transform_abs.get(entity) {
+                            matrix_abs_new = matrix_abs_new * &parent_transform_abs.matrix;
+                        }
+
+                        transform_abs.insert(child, ComponentTransformAbsolute {
+                            matrix: matrix_abs_new,
+                        }).expect("An error occurred while inserting a component into storage.");
+                    } else {
+                        transform_abs.remove(child);
                     }
                 }
 
-                transform_abs.insert(entity, ComponentTransformAbsolute {
-                    matrix: matrix_abs_new,
-                }).unwrap();
+                if modified || self.ancestors.contains(child.id()) {
+                    stack.push((child, modified));
+                }
             }
         }
     }
 }
 
+#[derive(Default)]
 pub struct SystemRender;
 
 impl<'a> System<'a> for SystemRender {
     type SystemData = (
         WriteExpect<'a, ResourceRenderData>,
         Read<'a, EntitiesRes>,
+        ReadExpect<'a, Hierarchy<ComponentParent>>,
+        ReadStorage<'a, ComponentVisible>,
         ReadStorage<'a, ComponentTransformAbsolute>,
         ReadStorage<'a, ComponentModel>,
+        ReadStorage<'a, ComponentLight>,
     );
 
-    fn run(&mut self, (mut render_data, entities, transform, model): Self::SystemData) {
+    fn run(&mut self, (mut render_data, entities, hierarchy, visible, transform, model, light): Self::SystemData) {
         render_data.world_space_models.clear();
+        render_data.lights.clear();
+        render_data.hidden.clear();
+
+        for (entity, visible) in (&entities, &visible).join() {
+            if !visible.visible && !render_data.hidden.add(entity.id()) {
+                for child in hierarchy.all_children_iter(entity) {
+                    render_data.hidden.add(child.id());
+                }
+            }
+        }
+
+        let render_data = &mut *render_data;
 
-        for (entity, transform, model) in (&entities, &transform, &model).join() {
-            render_data.world_space_models.push((entity.id(), transform.matrix.clone(), model.model.clone()));
+        for (entity, transform, model, _) in (&entities, &transform, &model, !&render_data.hidden).join() {
+            render_data.world_space_models.push((entity, transform.matrix.clone(), model.model.clone()));
         }
+
+        for (entity, transform, light, _) in (&entities, &transform, &light, !&render_data.hidden).join() {
+            render_data.lights.push((entity, transform.matrix.clone(), light.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ammolite_math::*;
+    use super::*;
+
+    fn transform_point(matrix: &Mat4, point: [f32; 3]) -> [f32; 3] {
+        (matrix * Vec3(point).into_homogeneous_position()).into_projected().0
+    }
+
+    #[test]
+    fn decomposition_reproduces_the_matrix() {
+        let length = (0.2f32 * 0.2 + 0.3 * 0.3 + 0.5 * 0.5 + 0.787 * 0.787).sqrt();
+        let rotations = [
+            Quaternion([0.0, 0.0, 0.0, 1.0]),
+            Quaternion([0.2 / length, -0.3 / length, 0.5 / length, 0.787 / length]),
+            Quaternion([0.0, std::f32::consts::FRAC_1_SQRT_2, 0.0, -std::f32::consts::FRAC_1_SQRT_2]),
+        ];
+        let scales = [[1.0, 1.0, 1.0], [2.0, 3.0, 0.5], [-1.0, 2.0, 1.0]];
+
+        for rotation in &rotations {
+            for scale in &scales {
+                let matrix = ComponentTransformDecomposed {
+                    translation: Vec3([1.0, -2.0, 3.0]),
+                    rotation: rotation.clone(),
+                    scale: Vec3(*scale),
+                }.to_matrix();
+                let decomposed = ComponentTransformDecomposed::from_matrix(&matrix).to_matrix();
+
+                for point in &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 2.0, 3.0]] {
+                    let expected = transform_point(&matrix, *point);
+                    let actual = transform_point(&decomposed, *point);
+
+                    for axis in 0..3 {
+                        assert!((expected[axis] - actual[axis]).abs() < 1e-4, "{:?} != {:?}", expected, actual);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lights_are_validated() {
+        let light = |kind, intensity, range| ComponentLight {
+            kind,
+            color: Vec3([1.0, 1.0, 1.0]),
+            intensity,
+            range,
+        };
+        let spot = |inner_cone_angle, outer_cone_angle| LightKind::Spot { inner_cone_angle, outer_cone_angle };
+
+        assert!(light(LightKind::Directional, 1.0, None).is_valid());
+        assert!(light(LightKind::Point, 0.0, Some(10.0)).is_valid());
+        assert!(light(spot(0.0, 0.0), 1.0, None).is_valid());
+        assert!(light(spot(0.2, 0.5), 1.0, Some(10.0)).is_valid());
+
+        assert!(!light(LightKind::Point, -1.0, None).is_valid());
+        assert!(!light(LightKind::Point, 1.0, Some(0.0)).is_valid());
+        assert!(!light(LightKind::Point, 1.0, Some(-5.0)).is_valid());
+        assert!(!light(spot(-0.1, 0.5), 1.0, None).is_valid());
+        assert!(!light(spot(0.6, 0.5), 1.0, None).is_valid());
+        assert!(!light(LightKind::Point, std::f32::NAN, None).is_valid());
+    }
+
+    #[test]
+    fn subtree_membership_follows_the_parents() {
+        let mut world = World::new();
+
+        world.register::<ComponentParent>();
+
+        let root = world.create_entity().build();
+        let child = world.create_entity().with(ComponentParent { entity: root }).build();
+        let grandchild = world.create_entity().with(ComponentParent { entity: child }).build();
+        let other = world.create_entity().build();
+        let cycle_a = world.create_entity().build();
+        let cycle_b = world.create_entity().with(ComponentParent { entity: cycle_a }).build();
+
+        world.write_storage::<ComponentParent>().insert(cycle_a, ComponentParent { entity: cycle_b }).unwrap();
+
+        let parents = world.read_storage::<ComponentParent>();
+
+        assert!(is_in_subtree(&parents, root, root));
+        assert!(is_in_subtree(&parents, grandchild, root));
+        assert!(is_in_subtree(&parents, grandchild, child));
+        assert!(!is_in_subtree(&parents, child, grandchild));
+        assert!(!is_in_subtree(&parents, other, root));
+        assert!(is_in_subtree(&parents, cycle_a, cycle_b));
+        assert!(!is_in_subtree(&parents, cycle_a, root));
     }
 }
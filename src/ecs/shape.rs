@@ -0,0 +1,710 @@
+//! Shape casts and overlap tests against the world-space triangles of the rendered models.
+//!
+//! Distances between a shape and a triangle are found with the GJK algorithm. Both are convex,
+//! so the distance is a convex function of the offset the shape is moved by along a line,
+//! which lets casts be solved by searching along the line.
+//!
+//! Models are treated as surfaces, so a shape entirely inside a closed model does not touch it.
+
+use crate::ecs::bvh::{Affine, Aabb, Bvh, BvhItem, SpatialItem};
+
+type Triangle = [[f32; 3]; 3];
+
+/// The number of iterations of the searches along a line, each of which narrows the interval
+/// to at most two thirds.
+const SEARCH_ITERATIONS: usize = 48;
+
+/// Distances up to which shapes are considered touching.
+const TOUCH_DISTANCE: f32 = 1e-4;
+
+/// The maximum number of iterations of the GJK algorithm, which usually converges in a few.
+const GJK_ITERATIONS: usize = 32;
+
+/// The relative improvement of the distance below which the GJK algorithm stops.
+const GJK_TOLERANCE: f32 = 1e-5;
+
+/// Squared lengths below which vectors are considered zero.
+const EPSILON_SQUARED: f32 = 1e-12;
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f32; 3], factor: f32) -> [f32; 3] {
+    [a[0] * factor, a[1] * factor, a[2] * factor]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn length(a: [f32; 3]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+/// Grows the box by `margin` in every direction.
+fn inflate(aabb: &Aabb, margin: f32) -> Aabb {
+    Aabb {
+        min: add(aabb.min, [-margin; 3]),
+        max: add(aabb.max, [margin; 3]),
+    }
+}
+
+/// Finds the minimum of a convex function between `start` and `end` by ternary search.
+fn minimize(mut start: f32, mut end: f32, function: impl Fn(f32) -> f32) -> f32 {
+    for _ in 0..SEARCH_ITERATIONS {
+        let a = start + (end - start) / 3.0;
+        let b = end - (end - start) / 3.0;
+
+        if function(a) <= function(b) {
+            end = b;
+        } else {
+            start = a;
+        }
+    }
+
+    (start + end) * 0.5
+}
+
+/// The columns of the rotation matrix of the quaternion, given as `[x, y, z, w]`.
+fn rotation_axes(rotation: [f32; 4]) -> [[f32; 3]; 3] {
+    let norm = (rotation[0] * rotation[0] + rotation[1] * rotation[1] + rotation[2] * rotation[2] + rotation[3] * rotation[3]).sqrt();
+
+    if norm == 0.0 {
+        return [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    }
+
+    let [x, y, z, w] = [rotation[0] / norm, rotation[1] / norm, rotation[2] / norm, rotation[3] / norm];
+
+    [
+        [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + w * z), 2.0 * (x * z - w * y)],
+        [2.0 * (x * y - w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + w * x)],
+        [2.0 * (x * z + w * y), 2.0 * (y * z - w * x), 1.0 - 2.0 * (x * x + y * y)],
+    ]
+}
+
+/// A convex volume in world space.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Volume {
+    Sphere { center: [f32; 3], radius: f32 },
+    /// A box rotated about its center by the quaternion `rotation`, given as `[x, y, z, w]`.
+    Box { center: [f32; 3], half_extents: [f32; 3], rotation: [f32; 4] },
+    /// The set of points within `radius` of the segment from `start` to `end`.
+    Capsule { start: [f32; 3], end: [f32; 3], radius: f32 },
+}
+
+impl Volume {
+    /// Checks that the coordinates are finite and that the radius and half-extents are
+    /// non-negative.
+    pub fn is_valid(&self) -> bool {
+        let is_finite = |point: &[f32]| point.iter().all(|component| component.is_finite());
+        let is_size = |size: f32| size.is_finite() && size >= 0.0;
+
+        match self {
+            Volume::Sphere { center, radius } => is_finite(center) && is_size(*radius),
+            Volume::Box { center, half_extents, rotation } =>
+                is_finite(center) && half_extents.iter().all(|half_extent| is_size(*half_extent)) && is_finite(rotation),
+            Volume::Capsule { start, end, radius } => is_finite(start) && is_finite(end) && is_size(*radius),
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            Volume::Sphere { center, radius } => Aabb {
+                min: add(*center, [-radius; 3]),
+                max: add(*center, [*radius; 3]),
+            },
+            Volume::Box { center, half_extents, rotation } => {
+                let axes = rotation_axes(*rotation);
+                let mut extent = [0.0; 3];
+
+                for (axis, half_extent) in axes.iter().zip(half_extents.iter()) {
+                    for component in 0..3 {
+                        extent[component] += axis[component].abs() * half_extent;
+                    }
+                }
+
+                Aabb {
+                    min: sub(*center, extent),
+                    max: add(*center, extent),
+                }
+            },
+            Volume::Capsule { start, end, radius } => Aabb {
+                min: add(*start, [-radius; 3]),
+                max: add(*start, [*radius; 3]),
+            }.union(&Aabb {
+                min: add(*end, [-radius; 3]),
+                max: add(*end, [*radius; 3]),
+            }),
+        }
+    }
+
+    /// The radius by which the core of the shape is rounded.
+    fn radius(&self) -> f32 {
+        match self {
+            Volume::Sphere { radius, .. } | Volume::Capsule { radius, .. } => *radius,
+            Volume::Box { .. } => 0.0,
+        }
+    }
+
+    /// The point of the core of the shape, moved by `offset`, farthest along `direction`.
+    /// The core of a sphere is its center, that of a capsule its segment.
+    fn support(&self, direction: [f32; 3], offset: [f32; 3]) -> [f32; 3] {
+        let point = match self {
+            Volume::Sphere { center, .. } => *center,
+            Volume::Box { center, half_extents, rotation } => {
+                let axes = rotation_axes(*rotation);
+
+                (0..3).fold(*center, |point, index| {
+                    let sign = if dot(direction, axes[index]) < 0.0 { -1.0 } else { 1.0 };
+
+                    add(point, scale(axes[index], sign * half_extents[index]))
+                })
+            },
+            Volume::Capsule { start, end, .. } => {
+                if dot(direction, sub(*end, *start)) > 0.0 { *end } else { *start }
+            },
+        };
+
+        add(point, offset)
+    }
+}
+
+fn triangle_support(triangle: &Triangle, direction: [f32; 3]) -> [f32; 3] {
+    let mut farthest = triangle[0];
+
+    for vertex in &triangle[1..] {
+        if dot(*vertex, direction) > dot(farthest, direction) {
+            farthest = *vertex;
+        }
+    }
+
+    farthest
+}
+
+/// A vertex of the simplex of the GJK algorithm: a point of the Minkowski difference of the
+/// shape and the triangle, along with the point of the triangle it was formed from.
+#[derive(Clone, Copy)]
+struct SimplexVertex {
+    point: [f32; 3],
+    on_triangle: [f32; 3],
+}
+
+/// The barycentric weights of the point of the segment closest to the origin.
+fn closest_on_segment(a: [f32; 3], b: [f32; 3]) -> [f32; 2] {
+    let ab = sub(b, a);
+    let denominator = dot(ab, ab);
+
+    if denominator <= EPSILON_SQUARED {
+        return [1.0, 0.0];
+    }
+
+    let t = (-dot(a, ab) / denominator).max(0.0).min(1.0);
+
+    [1.0 - t, t]
+}
+
+/// The barycentric weights of the point of the triangle closest to the origin, following
+/// "Real-Time Collision Detection" by Christer Ericson, section 5.1.5.
+fn closest_on_triangle(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> [f32; 3] {
+    let ab = sub(b, a);
+    let ac = sub(c, a);
+    let (d1, d2) = (-dot(ab, a), -dot(ac, a));
+
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return [1.0, 0.0, 0.0];
+    }
+
+    let (d3, d4) = (-dot(ab, b), -dot(ac, b));
+
+    if d3 >= 0.0 && d4 <= d3 {
+        return [0.0, 1.0, 0.0];
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return [1.0 - v, v, 0.0];
+    }
+
+    let (d5, d6) = (-dot(ab, c), -dot(ac, c));
+
+    if d6 >= 0.0 && d5 <= d6 {
+        return [0.0, 0.0, 1.0];
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return [1.0 - w, 0.0, w];
+    }
+
+    let va = d3 * d6 - d5 * d4;
+
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return [0.0, 1.0 - w, w];
+    }
+
+    let denominator = va + vb + vc;
+
+    // A degenerate triangle is as close as its closest edge.
+    if denominator.abs() <= EPSILON_SQUARED {
+        let edges = [(0, 1), (1, 2), (2, 0)];
+        let points = [a, b, c];
+        let mut best = ([1.0, 0.0, 0.0], std::f32::INFINITY);
+
+        for (i, j) in edges.iter() {
+            let [u, v] = closest_on_segment(points[*i], points[*j]);
+            let distance = length(add(scale(points[*i], u), scale(points[*j], v)));
+
+            if distance < best.1 {
+                let mut weights = [0.0; 3];
+
+                weights[*i] = u;
+                weights[*j] = v;
+                best = (weights, distance);
+            }
+        }
+
+        return best.0;
+    }
+
+    let v = vb / denominator;
+    let w = vc / denominator;
+
+    [1.0 - v - w, v, w]
+}
+
+/// The barycentric weights of the point of the tetrahedron closest to the origin.
+fn closest_on_tetrahedron(points: [[f32; 3]; 4]) -> [f32; 4] {
+    let [a, b, c, d] = points;
+    let (ab, ac, ad) = (sub(b, a), sub(c, a), sub(d, a));
+    let determinant = dot(ab, cross(ac, ad));
+
+    if determinant.abs() > EPSILON_SQUARED {
+        let origin = scale(a, -1.0);
+        let v = dot(origin, cross(ac, ad)) / determinant;
+        let w = dot(ab, cross(origin, ad)) / determinant;
+        let x = dot(ab, cross(ac, origin)) / determinant;
+
+        if v >= 0.0 && w >= 0.0 && x >= 0.0 && v + w + x <= 1.0 {
+            return [1.0 - v - w - x, v, w, x];
+        }
+    }
+
+    let faces = [[0, 1, 2], [0, 1, 3], [0, 2, 3], [1, 2, 3]];
+    let mut best = ([0.0; 4], std::f32::INFINITY);
+
+    for face in faces.iter() {
+        let face_weights = closest_on_triangle(points[face[0]], points[face[1]], points[face[2]]);
+        let mut weights = [0.0; 4];
+        let mut point = [0.0; 3];
+
+        for (index, weight) in face.iter().zip(face_weights.iter()) {
+            weights[*index] = *weight;
+            point = add(point, scale(points[*index], *weight));
+        }
+
+        let distance = dot(point, point);
+
+        if distance < best.1 {
+            best = (weights, distance);
+        }
+    }
+
+    best.0
+}
+
+/// Reduces the simplex to the vertices of its feature closest to the origin, and returns the
+/// closest point along with the corresponding point of the triangle.
+fn reduce_simplex(simplex: &mut [SimplexVertex; 4], len: &mut usize) -> ([f32; 3], [f32; 3]) {
+    let mut weights = [0.0; 4];
+
+    match *len {
+        1 => weights[0] = 1.0,
+        2 => weights[..2].copy_from_slice(&closest_on_segment(simplex[0].point, simplex[1].point)),
+        3 => weights[..3].copy_from_slice(&closest_on_triangle(simplex[0].point, simplex[1].point, simplex[2].point)),
+        _ => weights = closest_on_tetrahedron([simplex[0].point, simplex[1].point, simplex[2].point, simplex[3].point]),
+    }
+
+    let mut closest = [0.0; 3];
+    let mut on_triangle = [0.0; 3];
+    let mut reduced_len = 0;
+
+    for index in 0..*len {
+        if weights[index] > 0.0 {
+            closest = add(closest, scale(simplex[index].point, weights[index]));
+            on_triangle = add(on_triangle, scale(simplex[index].on_triangle, weights[index]));
+            simplex[reduced_len] = simplex[index];
+            reduced_len += 1;
+        }
+    }
+
+    *len = reduced_len;
+
+    (closest, on_triangle)
+}
+
+/// The distance between the core of the shape, moved by `offset`, and the triangle, along
+/// with the point of the triangle closest to the core.
+fn core_distance(volume: &Volume, triangle: &Triangle, offset: [f32; 3]) -> (f32, [f32; 3]) {
+    let support = |direction: [f32; 3]| {
+        let on_triangle = triangle_support(triangle, scale(direction, -1.0));
+
+        SimplexVertex {
+            point: sub(volume.support(direction, offset), on_triangle),
+            on_triangle,
+        }
+    };
+    let mut simplex = [support([1.0, 0.0, 0.0]); 4];
+    let mut len = 1;
+    let mut result = (std::f32::INFINITY, triangle[0]);
+
+    for _ in 0..GJK_ITERATIONS {
+        let (closest, on_triangle) = reduce_simplex(&mut simplex, &mut len);
+        let distance_squared = dot(closest, closest);
+
+        result = (distance_squared.sqrt(), on_triangle);
+
+        // The origin is enclosed by the simplex, so the shapes intersect.
+        if len == 4 || distance_squared <= EPSILON_SQUARED {
+            return (0.0, on_triangle);
+        }
+
+        let vertex = support(scale(closest, -1.0));
+        let duplicate = simplex[..len].iter().any(|existing| dot(sub(existing.point, vertex.point), sub(existing.point, vertex.point)) <= EPSILON_SQUARED);
+
+        if duplicate || distance_squared - dot(closest, vertex.point) <= GJK_TOLERANCE * distance_squared {
+            return result;
+        }
+
+        simplex[len] = vertex;
+        len += 1;
+    }
+
+    result
+}
+
+/// The distance between the shape, moved by `offset`, and the triangle, which is zero if they
+/// overlap, along with the point of the triangle closest to the shape.
+fn triangle_distance(volume: &Volume, triangle: &Triangle, offset: [f32; 3]) -> (f32, [f32; 3]) {
+    let (distance, contact_point) = core_distance(volume, triangle, offset);
+
+    ((distance - volume.radius()).max(0.0), contact_point)
+}
+
+/// Returns the point of the triangles closest to the shape, if the shape touches any of them.
+fn touch(volume: &Volume, triangles: &[Triangle]) -> Option<[f32; 3]> {
+    let mut closest: Option<(f32, [f32; 3])> = None;
+
+    for triangle in triangles {
+        let (distance, contact_point) = triangle_distance(volume, triangle, [0.0; 3]);
+
+        if distance <= TOUCH_DISTANCE && closest.map(|(closest_distance, _)| distance < closest_distance).unwrap_or(true) {
+            closest = Some((distance, contact_point));
+        }
+    }
+
+    closest.map(|(_, contact_point)| contact_point)
+}
+
+/// Moves the shape along the normalized `direction` by up to `max_distance` and returns the
+/// distance at which it first touches any of the triangles, along with the contact point.
+fn first_touch(volume: &Volume, triangles: &[Triangle], direction: [f32; 3], max_distance: f32) -> Option<(f32, [f32; 3])> {
+    let mut first: Option<(f32, [f32; 3])> = None;
+
+    for triangle in triangles {
+        let distance_at = |t: f32| triangle_distance(volume, triangle, scale(direction, t)).0;
+
+        if distance_at(0.0) <= TOUCH_DISTANCE {
+            return Some((0.0, triangle_distance(volume, triangle, [0.0; 3]).1));
+        }
+
+        // Only touches before the first one found so far are of interest. The distance is
+        // convex along the cast, so the first touch precedes its minimum.
+        let limit = first.map(|(distance, _)| distance).unwrap_or(max_distance);
+        let closest = minimize(0.0, limit, distance_at);
+
+        if distance_at(closest) > TOUCH_DISTANCE {
+            continue;
+        }
+
+        let mut start = 0.0;
+        let mut end = closest;
+
+        for _ in 0..SEARCH_ITERATIONS {
+            let middle = (start + end) * 0.5;
+
+            if distance_at(middle) > TOUCH_DISTANCE {
+                start = middle;
+            } else {
+                end = middle;
+            }
+        }
+
+        first = Some((end, triangle_distance(volume, triangle, scale(direction, end)).1));
+    }
+
+    first
+}
+
+fn triangle_bounds(triangle: &Triangle) -> Aabb {
+    triangle.iter().fold(Aabb::EMPTY, |bounds, vertex| bounds.union_point(*vertex))
+}
+
+/// The world-space triangles of the item's model whose bounds overlap `bounds`.
+/// The triangles are culled against the bounds brought into the model's space first, so that
+/// only the remaining ones are transformed.
+fn world_triangles(item: &SpatialItem, bounds: &Aabb) -> Vec<Triangle> {
+    let affine = Affine::from_matrix(&item.matrix);
+    // A transform without an inverse flattens the model, which is rare enough not to be culled.
+    let model_bounds = affine.inverse()
+        .map(|inverse| inverse.transform_bounds(bounds));
+
+    item.geometry.triangles.iter()
+        .filter(|triangle| {
+            model_bounds.map(|model_bounds| triangle_bounds(triangle).intersects(&model_bounds))
+                .unwrap_or(true)
+        })
+        .map(|triangle| [
+            affine.transform_point(triangle[0]),
+            affine.transform_point(triangle[1]),
+            affine.transform_point(triangle[2]),
+        ])
+        .filter(|triangle| triangle_bounds(triangle).intersects(bounds))
+        .collect()
+}
+
+/// An entity touched by a shape, and where.
+pub struct ShapeContact<'a> {
+    pub item: &'a BvhItem<SpatialItem>,
+    /// How far the shape was moved before touching the entity's model, zero for overlaps.
+    pub distance: f32,
+    /// The point of the model closest to the shape.
+    pub contact_point: [f32; 3],
+}
+
+/// Returns the items whose models the volume touches.
+pub fn overlap<'a>(bvh: &'a Bvh<SpatialItem>, volume: &Volume) -> Vec<ShapeContact<'a>> {
+    let bounds = inflate(&volume.bounds(), TOUCH_DISTANCE);
+
+    bvh.overlapping(&bounds).into_iter()
+        .filter_map(|item| {
+            touch(volume, &world_triangles(&item.value, &bounds)).map(|contact_point| ShapeContact {
+                item,
+                distance: 0.0,
+                contact_point,
+            })
+        })
+        .collect()
+}
+
+/// Moves the volume along `direction` by up to `max_distance` and returns the items whose models
+/// it touches, ordered by the distance at which it first touches them.
+/// The volume, direction and distance must be finite, see `Volume::is_valid`.
+pub fn cast<'a>(bvh: &'a Bvh<SpatialItem>, volume: &Volume, direction: [f32; 3], max_distance: f32) -> Vec<ShapeContact<'a>> {
+    let direction_length = length(direction);
+
+    if direction_length == 0.0 || max_distance <= 0.0 {
+        return overlap(bvh, volume);
+    }
+
+    let direction = scale(direction, 1.0 / direction_length);
+    let start_bounds = volume.bounds();
+    let end_bounds = Aabb {
+        min: add(start_bounds.min, scale(direction, max_distance)),
+        max: add(start_bounds.max, scale(direction, max_distance)),
+    };
+    let bounds = inflate(&start_bounds.union(&end_bounds), TOUCH_DISTANCE);
+    let mut contacts: Vec<ShapeContact> = bvh.overlapping(&bounds).into_iter()
+        .filter_map(|item| {
+            let triangles = world_triangles(&item.value, &bounds);
+
+            first_touch(volume, &triangles, direction, max_distance).map(|(distance, contact_point)| ShapeContact {
+                item,
+                distance,
+                contact_point,
+            })
+        })
+        .collect();
+
+    contacts.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal));
+    contacts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+    /// A right triangle in the plane z = 0, with its right angle at the origin.
+    const TRIANGLE: Triangle = [[0.0, 0.0, 0.0], [4.0, 0.0, 0.0], [0.0, 4.0, 0.0]];
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "{} != {}", actual, expected);
+    }
+
+    fn assert_point_close(actual: [f32; 3], expected: [f32; 3]) {
+        for axis in 0..3 {
+            assert!((actual[axis] - expected[axis]).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    /// A rotation by the angle about the z axis.
+    fn rotation_z(angle: f32) -> [f32; 4] {
+        [0.0, 0.0, (angle * 0.5).sin(), (angle * 0.5).cos()]
+    }
+
+    fn sphere(center: [f32; 3], radius: f32) -> Volume {
+        Volume::Sphere { center, radius }
+    }
+
+    #[test]
+    fn oriented_box_bounds() {
+        let volume = Volume::Box { center: [1.0, 2.0, 3.0], half_extents: [1.0, 2.0, 3.0], rotation: IDENTITY };
+
+        assert_eq!(volume.bounds(), Aabb { min: [0.0, 0.0, 0.0], max: [2.0, 4.0, 6.0] });
+
+        let bounds = Volume::Box { center: [0.0; 3], half_extents: [1.0, 1.0, 1.0], rotation: rotation_z(std::f32::consts::FRAC_PI_4) }.bounds();
+
+        assert_point_close(bounds.max, [2.0f32.sqrt(), 2.0f32.sqrt(), 1.0]);
+        assert_point_close(bounds.min, [-(2.0f32.sqrt()), -(2.0f32.sqrt()), -1.0]);
+    }
+
+    #[test]
+    fn volumes_are_validated() {
+        assert!(sphere([0.0; 3], 0.0).is_valid());
+        assert!(Volume::Box { center: [0.0; 3], half_extents: [1.0, 0.0, 2.0], rotation: IDENTITY }.is_valid());
+        assert!(Volume::Capsule { start: [0.0; 3], end: [1.0, 0.0, 0.0], radius: 0.5 }.is_valid());
+
+        assert!(!sphere([0.0; 3], -1.0).is_valid());
+        assert!(!sphere([0.0; 3], std::f32::INFINITY).is_valid());
+        assert!(!sphere([std::f32::NAN, 0.0, 0.0], 1.0).is_valid());
+        assert!(!Volume::Box { center: [0.0; 3], half_extents: [1.0, -1.0, 1.0], rotation: IDENTITY }.is_valid());
+        assert!(!Volume::Box { center: [0.0; 3], half_extents: [1.0; 3], rotation: [std::f32::NAN; 4] }.is_valid());
+        assert!(!Volume::Capsule { start: [0.0; 3], end: [std::f32::INFINITY, 0.0, 0.0], radius: 0.5 }.is_valid());
+    }
+
+    #[test]
+    fn sphere_distances_to_the_triangle() {
+        // Above the interior, beyond an edge, beyond a vertex.
+        let (distance, contact_point) = triangle_distance(&sphere([1.0, 1.0, 3.0], 1.0), &TRIANGLE, [0.0; 3]);
+
+        assert_close(distance, 2.0);
+        assert_point_close(contact_point, [1.0, 1.0, 0.0]);
+
+        let (distance, contact_point) = triangle_distance(&sphere([3.0, 3.0, 0.0], 0.5), &TRIANGLE, [0.0; 3]);
+
+        assert_close(distance, 2.0f32.sqrt() - 0.5);
+        assert_point_close(contact_point, [2.0, 2.0, 0.0]);
+
+        let (distance, contact_point) = triangle_distance(&sphere([-3.0, -4.0, 0.0], 1.0), &TRIANGLE, [0.0; 3]);
+
+        assert_close(distance, 4.0);
+        assert_point_close(contact_point, [0.0, 0.0, 0.0]);
+
+        assert_eq!(triangle_distance(&sphere([1.0, 1.0, 0.5], 1.0), &TRIANGLE, [0.0; 3]).0, 0.0);
+        assert_close(triangle_distance(&sphere([1.0, 1.0, 0.5], 1.0), &TRIANGLE, [0.0, 0.0, 2.0]).0, 1.5);
+    }
+
+    #[test]
+    fn capsule_and_box_distances_to_the_triangle() {
+        let capsule = Volume::Capsule { start: [-5.0, 1.0, 2.0], end: [5.0, 1.0, 2.0], radius: 0.5 };
+
+        assert_close(triangle_distance(&capsule, &TRIANGLE, [0.0; 3]).0, 1.5);
+
+        // A segment passing through the triangle intersects it.
+        let capsule = Volume::Capsule { start: [1.0, 1.0, -1.0], end: [1.0, 1.0, 1.0], radius: 0.1 };
+
+        assert_eq!(triangle_distance(&capsule, &TRIANGLE, [0.0; 3]).0, 0.0);
+
+        let aligned = Volume::Box { center: [1.0, 1.0, 2.0], half_extents: [1.0, 1.0, 1.0], rotation: IDENTITY };
+
+        assert_close(triangle_distance(&aligned, &TRIANGLE, [0.0; 3]).0, 1.0);
+
+        // Rotating the box about the x axis by 45 degrees brings an edge closer to the triangle.
+        let angle = std::f32::consts::FRAC_PI_4;
+        let rotated = Volume::Box {
+            center: [1.0, 1.0, 2.0],
+            half_extents: [1.0, 1.0, 1.0],
+            rotation: [(angle * 0.5).sin(), 0.0, 0.0, (angle * 0.5).cos()],
+        };
+
+        assert_close(triangle_distance(&rotated, &TRIANGLE, [0.0; 3]).0, 2.0 - 2.0f32.sqrt());
+
+        // Beyond the hypotenuse, a box rotated to face it is closer than an unrotated one.
+        let center = [3.5, 3.5, 0.0];
+        let facing = Volume::Box { center, half_extents: [0.5, 0.5, 0.5], rotation: rotation_z(std::f32::consts::FRAC_PI_4) };
+        let unrotated = Volume::Box { center, half_extents: [0.5, 0.5, 0.5], rotation: IDENTITY };
+
+        assert_close(triangle_distance(&facing, &TRIANGLE, [0.0; 3]).0, 1.5 * 2.0f32.sqrt() - 0.5);
+        assert_close(triangle_distance(&unrotated, &TRIANGLE, [0.0; 3]).0, 1.5 * 2.0f32.sqrt() - 0.5 * 2.0f32.sqrt());
+    }
+
+    #[test]
+    fn degenerate_triangles_are_segments() {
+        let triangle = [[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [4.0, 0.0, 0.0]];
+
+        assert_close(triangle_distance(&sphere([1.0, 3.0, 0.0], 1.0), &triangle, [0.0; 3]).0, 2.0);
+        assert_close(triangle_distance(&sphere([6.0, 0.0, 0.0], 1.0), &triangle, [0.0; 3]).0, 1.0);
+    }
+
+    #[test]
+    fn overlaps_touch_surfaces_only() {
+        // Two triangles forming the square [0, 4] x [0, 4] in the plane z = 0.
+        let square = [TRIANGLE, [[4.0, 0.0, 0.0], [4.0, 4.0, 0.0], [0.0, 4.0, 0.0]]];
+
+        assert_point_close(touch(&sphere([3.0, 3.0, 0.5], 1.0), &square).unwrap(), [3.0, 3.0, 0.0]);
+        assert!(touch(&sphere([3.0, 3.0, 1.5], 1.0), &square).is_none());
+        assert!(touch(&sphere([6.0, 2.0, 0.0], 1.0), &square).is_none());
+    }
+
+    #[test]
+    fn casts_find_the_first_touch() {
+        let down = [0.0, 0.0, -1.0];
+        let (distance, contact_point) = first_touch(&sphere([1.0, 1.0, 5.0], 1.0), &[TRIANGLE], down, 10.0).unwrap();
+
+        assert_close(distance, 4.0);
+        assert_point_close(contact_point, [1.0, 1.0, 0.0]);
+
+        // Too short to reach, or passing beside the triangle.
+        assert!(first_touch(&sphere([1.0, 1.0, 5.0], 1.0), &[TRIANGLE], down, 3.0).is_none());
+        assert!(first_touch(&sphere([5.0, 5.0, 5.0], 1.0), &[TRIANGLE], down, 10.0).is_none());
+
+        // Already touching.
+        assert_eq!(first_touch(&sphere([1.0, 1.0, 0.5], 1.0), &[TRIANGLE], down, 10.0).map(|(distance, _)| distance), Some(0.0));
+
+        // A box standing on its edge reaches the plane sooner.
+        let angle = std::f32::consts::FRAC_PI_4;
+        let rotated = Volume::Box {
+            center: [1.0, 1.0, 5.0],
+            half_extents: [0.5, 0.5, 0.5],
+            rotation: [(angle * 0.5).sin(), 0.0, 0.0, (angle * 0.5).cos()],
+        };
+
+        assert_close(first_touch(&rotated, &[TRIANGLE], down, 10.0).unwrap().0, 5.0 - 0.5 * 2.0f32.sqrt());
+
+        // The closer of two triangles is touched first, regardless of their order.
+        let lower = [[0.0, 0.0, -2.0], [4.0, 0.0, -2.0], [0.0, 4.0, -2.0]];
+        let capsule = Volume::Capsule { start: [0.5, 0.5, 3.0], end: [2.0, 0.5, 3.0], radius: 0.25 };
+
+        assert_close(first_touch(&capsule, &[lower, TRIANGLE], down, 10.0).unwrap().0, 2.75);
+        assert_close(first_touch(&capsule, &[TRIANGLE, lower], down, 10.0).unwrap().0, 2.75);
+    }
+}
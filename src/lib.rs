@@ -1,16 +1,13 @@
 //! TODO:
 //! * Example App #2 -- Interactions with entities
-//! * Asynchronous execution of applications
 //! * Convert command message passing using the exports to exports/imports
 //!   (see wasmtime-api and wasmtime-interface-types)
 //! * Camera movement (App prioritization?)
-//! * Applications as libraries? Inter-application communication?
+//! * Applications as libraries?
 //!
 //! Most likely cancelled because of the transition to webgpu:
-//! * Add entity deletion
-//! * Figure out a way to represent point lights:
-//!   - abuse glTF scenes, which you can use to store light sources with;
-//!   - or use an explicit representation for light sources
+//! * Shade with the light sources: `ComponentLight`s are collected into
+//!   `ResourceRenderData::lights`, but are not passed to the renderer yet
 //! * Figure out how to represent cameras/views in the scene graph and how to
 //!   render geometry relative to the cameras/views. Ideas:
 //!   - Make HMDs proper entities of the scene graph
@@ -21,6 +18,9 @@ extern crate test;
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
 use winit::{
     event_loop::EventLoop,
     window::WindowBuilder,
@@ -33,15 +33,25 @@ use ammolite::camera::PitchYawCamera3;
 use lazy_static::lazy_static;
 use specs::prelude::*;
 use specs_hierarchy::HierarchySystem;
-use ::mlib::MappInterface;
+use ::mlib::{MappInterface, MappHandle, Event};
 use crate::medium::{MediumData, SpecializedMediumData};
 use crate::ecs::*;
-use crate::vm::{Mapp, MappExports, MappContainer};
+use crate::ecs::animation::{SystemAnimation, ResourceFinishedAnimations};
+use crate::ecs::bvh::SystemSpatialIndex;
+use crate::vm::{MappContainer, MappState, CrashPolicy};
 use crate::vm::event::{DeviceStore, EventDistributor};
+use crate::vm::capability::Capability;
+use crate::vm::error::MappLoadError;
+use crate::vm::fuel;
+use crate::vm::manifest::MappManifest;
+use crate::vm::reload::FileWatcher;
+use crate::vm::model_cache::{ModelCache, ModelLoadUpdate};
+use crate::scene::Scene;
 
 pub mod medium;
 pub mod ecs;
 pub mod vm;
+pub mod scene;
 
 lazy_static! {
     static ref PACKAGE_VERSION: (u16, u16, u16) = (
@@ -62,9 +72,20 @@ pub struct Metaview {
     pub camera: Rc<RefCell<PitchYawCamera3>>,
     pub hmd_poses: Vec<(Rc<RefCell<Vec3>>, Rc<RefCell<Vec3>>)>,
     pub ammolite: Ammolite<MediumData>,
+    /// Models shared between mapps, keyed by the hash of the bytes they were loaded from.
+    pub model_cache: ModelCache,
     pub world: World,
     pub dispatcher: Dispatcher<'static, 'static>,
     pub mappcs: Vec<MappContainer>,
+    /// Capabilities granted to every loaded mapp, in addition to those requested by its manifest.
+    pub default_capabilities: HashSet<Capability>,
+    /// The fuel each call into a wasm mapp may consume, see `vm::fuel`, or `None` for no limit.
+    /// Applies to the mapps loaded afterwards.
+    pub mapp_call_fuel: Option<u64>,
+    pub crash_policy: CrashPolicy,
+    next_mapp_handle: usize,
+    /// The scene read by `load_scene`, whose mapps `load_mapps` restores, along with its path.
+    pending_scene: Option<(PathBuf, Scene)>,
 }
 
 impl Metaview {
@@ -131,9 +152,12 @@ impl Metaview {
 
         let mut dispatcher = DispatcherBuilder::new()
             .with(HierarchySystem::<ComponentParent>::new(&mut world), "system_hierarchy", &[])
+            .with(SystemAnimation, "system_animation", &[])
+            .with(SystemTransformCompose::default(), "system_transform_compose", &["system_animation"])
             .with_barrier()
-            .with(SystemTransformInheritance, "system_transform_inheritance", &[])
-            .with_thread_local(SystemRender)
+            .with(SystemTransformInheritance::default(), "system_transform_inheritance", &[])
+            .with_thread_local(SystemRender::default())
+            .with_thread_local(SystemSpatialIndex::default())
             .build();
 
         dispatcher.setup(&mut world);
@@ -150,26 +174,65 @@ impl Metaview {
             camera,
             hmd_poses,
             ammolite,
+            model_cache: ModelCache::new(),
             world,
             dispatcher,
             mappcs: Vec::new(),
+            default_capabilities: Capability::DEFAULT.iter().cloned().collect(),
+            mapp_call_fuel: Some(fuel::DEFAULT_CALL_FUEL),
+            crash_policy: CrashPolicy::Freeze,
+            next_mapp_handle: 0,
+            pending_scene: None,
         }
     }
 
-    pub fn load_mapps<T: AsRef<str>>(&mut self, mapp_paths: impl IntoIterator<Item=T>) {
+    /// Loads the mapps described by the given manifests or bare wasm modules.
+    ///
+    /// A mapp that fails to load is skipped, so that its siblings keep running, and the errors
+    /// of the skipped mapps are returned. An error is only returned if no mapp could be loaded
+    /// at all. Mapps in the scene read by `load_scene` are restored instead of instantiated.
+    pub fn load_mapps<T: AsRef<str>>(&mut self, mapp_paths: impl IntoIterator<Item=T>) -> Result<Vec<MappLoadError>, MappLoadError> {
         // Check arguments
         let mapp_paths: Vec<String> = mapp_paths.into_iter()
             .map(|item| item.as_ref().to_string())
             .collect::<Vec<_>>();
 
+        // Validate all manifests before instantiating any mapp
+        let mut manifests: Vec<MappManifest> = Vec::with_capacity(mapp_paths.len());
+        let mut errors: Vec<MappLoadError> = Vec::new();
+
+        for mapp_path in &mapp_paths {
+            let manifest = MappManifest::from_path(mapp_path)
+                .and_then(|manifest| {
+                    manifest.validate_unique_name(mapp_path.as_ref(), &manifests)?;
+                    Ok(manifest)
+                });
+
+            match manifest {
+                Ok(manifest) => manifests.push(manifest),
+                Err(error) => errors.push(error.into()),
+            }
+        }
+
         // Load Mapps
-        self.mappcs = mapp_paths.into_iter().map(|mapp_path| {
-            let mapp_exports = MappExports::load_file(mapp_path)
-                .expect("Could not load the Example MApp.");
-            let mapp = Mapp::initialize(mapp_exports);
+        for manifest in manifests {
+            let result = match self.take_scene_mapp(&manifest.name) {
+                Some((scene_path, scene_mapp)) => {
+                    self.restore_mapp(&scene_path, manifest.clone(), scene_mapp)
+                        .or_else(|error| {
+                            eprintln!("Could not restore mapp {}, instantiating it instead: {}", manifest.name, error);
+                            self.load_mapp(manifest)
+                        })
+                },
+                None => self.load_mapp(manifest),
+            };
 
-            MappContainer::from_wasm(mapp, &mut self.world)
-        }).collect::<Vec<_>>();
+            if let Err(error) = result {
+                errors.push(error);
+            }
+        }
+
+        self.discard_scene();
 
         #[cfg(feature = "native-example-mapp")]
         {
@@ -180,27 +243,209 @@ impl Metaview {
 
             //     MappContainer::new(mapp_interface, &mut world)
             // });
+            let handle = self.allocate_mapp_handle();
+
             self.mappcs.push({
-                use example_mapp_2::{Mapp, ExampleMapp};
-                let mapp = ExampleMapp::new();
-                let mapp_interface: Box<dyn MappInterface> = Box::new(mapp);
+                let mut manifest = MappManifest::native("example-mapp-2", "0.1.0");
+
+                // Native mapps are compiled into the host and therefore trusted
+                manifest.capabilities.extend(Capability::ALL.iter());
+
+                let mappc = MappContainer::new(|| -> Result<Box<dyn MappInterface>, std::convert::Infallible> {
+                    use example_mapp_2::{Mapp, ExampleMapp};
+                    let mapp = ExampleMapp::new();
 
-                MappContainer::new(mapp_interface, &mut self.world)
+                    Ok(Box::new(mapp))
+                }, handle, manifest, &mut self.world);
+
+                self.event_distributor.register_mapp(mappc.name(), mappc.handle);
+
+                mappc
             });
         }
 
         if self.mappcs.is_empty() {
-            eprintln!("At least one metaview application must be specified.");
-            return;
+            return Err(MappLoadError::NoMapps { errors });
         }
 
+        // The mapps are constructed in the background; the commands of those that are ready
+        // already are applied right away.
         for mappc in &mut self.mappcs {
-            mappc.process_io();
-            mappc.process_commands(&mut self.ammolite, &mut self.world, &self.camera, true);
+            mappc.process_commands(&mut self.ammolite, &mut self.model_cache, &mut self.world, &self.camera, &self.event_distributor);
         }
 
-        self.event_distributor.distribute_events(&mut self.mappcs[..], &mut self.ammolite, &mut self.world, &self.camera);
+        self.unload_exited_mapps();
+
+        println!("Mapps loaded.");
+
+        Ok(errors)
+    }
+
+    /// Instantiates the wasm mapp described by a validated manifest.
+    pub fn load_mapp(&mut self, manifest: MappManifest) -> Result<(), MappLoadError> {
+        println!("Loading mapp {} {}.", manifest.name, manifest.version);
+
+        let handle = self.allocate_mapp_handle();
+        let mut mappc = MappContainer::from_wasm(handle, manifest, self.mapp_call_fuel, &mut self.world)?;
+
+        mappc.capabilities.extend(&self.default_capabilities);
+
+        self.event_distributor.register_mapp(mappc.name(), mappc.handle);
+        self.mappcs.push(mappc);
+
+        Ok(())
+    }
+
+    fn allocate_mapp_handle(&mut self) -> MappHandle {
+        let handle = MappHandle(self.next_mapp_handle);
+
+        self.next_mapp_handle += 1;
+
+        handle
+    }
+
+    /// Asks each mapp to update, forwards the pending events and applies the commands the
+    /// mapps have sent since the previous frame. The mapps run on their own threads, so
+    /// this does not wait for them.
+    pub fn update_mapps(&mut self, elapsed: Duration) {
+        for mappc in &mut self.mappcs {
+            mappc.update(elapsed);
+        }
+
+        self.send_animation_events();
+        self.event_distributor.distribute_events(&mut self.mappcs[..]);
+
+        for mappc in &mut self.mappcs {
+            mappc.process_commands(&mut self.ammolite, &mut self.model_cache, &mut self.world, &self.camera, &self.event_distributor);
+
+            // Messages to a crashed or unresponsive mapp would never be received.
+            if mappc.state.has_terminated() {
+                self.event_distributor.unregister_mapp(mappc.name());
+            }
+        }
+
+        for update in self.model_cache.poll(&mut self.ammolite) {
+            for mappc in &mut self.mappcs {
+                match &update {
+                    ModelLoadUpdate::Progress(hash, progress) => mappc.report_model_load_progress(*hash, *progress),
+                    ModelLoadUpdate::Finished(hash, result) => mappc.finish_model_load(*hash, result),
+                }
+            }
+        }
+    }
 
-        println!("Mapps initialized.");
+    /// Whether any of the mapps is still running and will not be unloaded by the next call
+    /// of `unload_exited_mapps`. Crashed mapps that were kept in the scene do not count, as
+    /// they will never exit on their own, and neither do restored mapps, which never run.
+    pub fn has_running_mapps(&self) -> bool {
+        self.mappcs.iter().any(|mappc| mappc.state == MappState::Running && !mappc.exit_requested)
+    }
+
+    /// Whether any of the mapps was restored from a scene, whose entities stay static until
+    /// the window is closed.
+    pub fn has_restored_mapps(&self) -> bool {
+        self.mappcs.iter().any(|mappc| mappc.state == MappState::Restored)
+    }
+
+    /// Notifies the mapps of their animations that ended during the previous dispatch.
+    fn send_animation_events(&mut self) {
+        let finished = std::mem::replace(&mut self.world.write_resource::<ResourceFinishedAnimations>().0, Vec::new());
+
+        for entity in finished {
+            for mappc in &mut self.mappcs {
+                if let Some(handle) = mappc.entity_handles.owned_handle(entity) {
+                    mappc.send_event(Event::AnimationFinished { entity: handle });
+                }
+            }
+        }
+    }
+
+    /// Reinstantiates the wasm mapps whose modules have been modified on disk.
+    /// The previous instance's scene subtree is removed and the new instance is initialized
+    /// in its place, while the other mapps keep running. The previous instance is only kept,
+    /// if the modified module is invalid; a failed instantiation is reported as a crash.
+    pub fn reload_modified_mapps(&mut self) {
+        for index in 0..self.mappcs.len() {
+            let modified = self.mappcs[index].watcher.as_mut()
+                .map(FileWatcher::poll)
+                .unwrap_or(false);
+
+            if !modified {
+                continue;
+            }
+
+            let handle = self.mappcs[index].handle;
+            let manifest = self.mappcs[index].manifest.clone();
+            let name = manifest.name.clone();
+            let mut mappc = match MappContainer::from_wasm(handle, manifest, self.mapp_call_fuel, &mut self.world) {
+                Ok(mappc) => mappc,
+                Err(error) => {
+                    eprintln!("Could not reload mapp {}, keeping the previous instance: {}", name, error);
+                    continue;
+                },
+            };
+
+            mappc.capabilities = self.mappcs[index].capabilities.clone();
+
+            // The previous instance might have been unregistered, if it crashed.
+            self.event_distributor.register_mapp(mappc.name(), mappc.handle);
+
+            let previous_mappc = std::mem::replace(&mut self.mappcs[index], mappc);
+            previous_mappc.unload(&mut self.world);
+
+            let mappc = &mut self.mappcs[index];
+            mappc.process_commands(&mut self.ammolite, &mut self.model_cache, &mut self.world, &self.camera, &self.event_distributor);
+
+            println!("Mapp {} reloaded from {}.", mappc.name(), mappc.manifest.entry_path().display());
+
+            self.prune_entity_handles();
+        }
+    }
+
+    /// Removes every mapp that has sent `CommandKind::Exit`, along with its scene subtree.
+    /// Crashed and unresponsive mapps are removed as well, if the crash policy says so, and are
+    /// no longer reachable by messages otherwise.
+    pub fn unload_exited_mapps(&mut self) {
+        let mut index = 0;
+        let mut unloaded = false;
+
+        while index < self.mappcs.len() {
+            let crashed = self.mappcs[index].state.has_terminated();
+
+            if crashed {
+                self.event_distributor.unregister_mapp(self.mappcs[index].name());
+            }
+
+            if self.mappcs[index].exit_requested || (crashed && self.crash_policy == CrashPolicy::Remove) {
+                let mappc = self.mappcs.remove(index);
+
+                self.event_distributor.unregister_mapp(mappc.name());
+
+                if crashed {
+                    println!("Mapp {} crashed and was unloaded.", mappc.name());
+                } else {
+                    println!("Mapp {} exited and was unloaded.", mappc.name());
+                }
+
+                mappc.unload(&mut self.world);
+                unloaded = true;
+            } else {
+                index += 1;
+            }
+        }
+
+        if unloaded {
+            self.prune_entity_handles();
+        }
+    }
+
+    /// Forgets the handles of every mapp that refer to deleted entities, such as the subtree
+    /// of an unloaded mapp.
+    pub fn prune_entity_handles(&mut self) {
+        let entities = self.world.entities();
+
+        for mappc in &mut self.mappcs {
+            mappc.entity_handles.prune(&entities);
+        }
     }
 }
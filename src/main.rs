@@ -1,16 +1,13 @@
 //! TODO:
 //! * Example App #2 -- Interactions with entities
-//! * Asynchronous execution of applications
 //! * Convert command message passing using the exports to exports/imports
 //!   (see wasmtime-api and wasmtime-interface-types)
 //! * Camera movement (App prioritization?)
-//! * Applications as libraries? Inter-application communication?
+//! * Applications as libraries?
 //!
 //! Most likely cancelled because of the transition to webgpu:
-//! * Add entity deletion
-//! * Figure out a way to represent point lights:
-//!   - abuse glTF scenes, which you can use to store light sources with;
-//!   - or use an explicit representation for light sources
+//! * Shade with the light sources: `ComponentLight`s are collected into
+//!   `ResourceRenderData::lights`, but are not passed to the renderer yet
 //! * Figure out how to represent cameras/views in the scene graph and how to
 //!   render geometry relative to the cameras/views. Ideas:
 //!   - Make HMDs proper entities of the scene graph
@@ -36,13 +33,63 @@ use crate::ecs::*;
 use crate::vm::{Mapp, MappExports, MappContainer};
 use crate::vm::event::{DeviceStore, EventDistributor};
 use metaview_lib::*;
+use metaview_lib::scene::models_directory;
+
+/// Saves the scene to the path in `METAVIEW_SCENE_SAVE`, if set.
+fn save_scene(metaview: &Metaview) {
+    if let Ok(scene_path) = std::env::var("METAVIEW_SCENE_SAVE") {
+        if let Err(error) = metaview.save_scene(&scene_path) {
+            eprintln!("Could not save scene: {}", error);
+        }
+    }
+}
 
 fn main() {
     let mapp_paths = std::env::args().skip(1);
     let mut metaview = Metaview::new();
 
+    if let Ok(call_fuel) = std::env::var("METAVIEW_MAPP_CALL_FUEL") {
+        let call_fuel: u64 = call_fuel.parse()
+            .expect("METAVIEW_MAPP_CALL_FUEL must be a non-negative integer.");
+
+        // A budget of zero disables the limit
+        metaview.mapp_call_fuel = if call_fuel > 0 {
+            Some(call_fuel)
+        } else {
+            None
+        };
+    }
+
+    // The models are stored next to the scene as they are loaded, so that they need not be
+    // kept in memory until the scene is saved.
+    if let Ok(scene_path) = std::env::var("METAVIEW_SCENE_SAVE") {
+        let directory = models_directory(scene_path.as_ref());
+
+        if let Err(error) = metaview.model_cache.set_source_directory(directory.clone()) {
+            eprintln!("Could not create {}, models will not be saved: {}", directory.display(), error);
+        }
+    }
+
+    // The scene has to be read before the mapps are loaded, so that the mapps it contains are
+    // restored instead of instantiated.
+    if let Ok(scene_path) = std::env::var("METAVIEW_SCENE") {
+        if let Err(error) = metaview.load_scene(&scene_path) {
+            eprintln!("Could not load scene: {}", error);
+        }
+    }
+
     // let bench_start = Instant::now();
-    metaview.load_mapps(mapp_paths);
+    match metaview.load_mapps(mapp_paths) {
+        Ok(errors) => {
+            for error in errors {
+                eprintln!("Could not load mapp: {}", error);
+            }
+        },
+        Err(error) => {
+            eprintln!("Could not load mapps: {}", error);
+            return;
+        },
+    }
     // println!("Duration: {:?}", bench_start.elapsed());
     // return;
 
@@ -75,16 +122,22 @@ fn main() {
         previous_frame_instant = now;
 
         if metaview.ammolite.handle_events(&delta_time) {
+            save_scene(&metaview);
             break;
         }
 
-        for mappc in &mut metaview.mappcs {
-            mappc.mapp.update(elapsed);
-            mappc.process_io();
-            mappc.process_commands(&mut metaview.ammolite, &mut metaview.world, &metaview.camera, true);
+        metaview.reload_modified_mapps();
+        metaview.update_mapps(elapsed);
+
+        // Saved before the exited mapps are unloaded along with their entities.
+        // A restored scene is kept on display until the window is closed.
+        if !metaview.has_running_mapps() && !metaview.has_restored_mapps() {
+            save_scene(&metaview);
+            println!("No mapps are running anymore.");
+            break;
         }
 
-        metaview.event_distributor.distribute_events(&mut metaview.mappcs[..], &mut metaview.ammolite, &mut metaview.world, &metaview.camera);
+        metaview.unload_exited_mapps();
 
         metaview.dispatcher.dispatch(&mut metaview.world);
 
@@ -0,0 +1,713 @@
+//! Scenes are saved as a json5 document, such as:
+//!
+//! ```json5
+//! {
+//!     mapps: [
+//!         {
+//!             name: "example-mapp",
+//!             models: [ "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08" ],
+//!             entities: [
+//!                 { transform: [ /* 16 elements */ ] },
+//!                 { parent: 0, model: 0, name: "cube", tags: [ "interactive" ] },
+//!             ],
+//!         },
+//!     ],
+//! }
+//! ```
+//!
+//! The first entity of each mapp is its root entity. Parents and models are referred to by
+//! their index within the mapp, and each parent precedes its children. The bytes of each
+//! model are stored in a file named after their hash, in a directory next to the document,
+//! see `models_directory`.
+//!
+//! A mapp whose entities are restored from a scene is not instantiated, so that it does not
+//! add its entities to the restored ones, see `MappState::Restored`. Restored scenes are
+//! therefore static: their entities are neither updated nor animated by the mapp, which
+//! receives no events and does not count as running.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use ammolite_math::{Mat4, Vec3, Quaternion};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use crate::Metaview;
+use crate::ecs::*;
+use crate::vm::MappContainer;
+use crate::vm::manifest::MappManifest;
+use crate::vm::model_cache::{ModelHash, hash_to_hex};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub mapps: Vec<SceneMapp>,
+}
+
+/// The entities owned by a single mapp.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneMapp {
+    /// The name of the mapp, as in its manifest.
+    pub name: String,
+    /// The hex-encoded hashes of the models.
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub entities: Vec<SceneEntity>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneEntity {
+    /// The index of the parent entity, or `None` for the root entity and for entities
+    /// detached from the scene.
+    #[serde(default)]
+    pub parent: Option<usize>,
+    #[serde(default)]
+    pub transform: Option<Mat4>,
+    #[serde(default)]
+    pub transform_decomposed: Option<SceneTransformDecomposed>,
+    /// The index of the model.
+    #[serde(default)]
+    pub model: Option<usize>,
+    #[serde(default)]
+    pub visible: Option<bool>,
+    #[serde(default)]
+    pub light: Option<SceneLight>,
+    #[serde(default)]
+    pub layers: Option<u32>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// See `ComponentTransformDecomposed`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneTransformDecomposed {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+}
+
+/// See `ComponentLight`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneLight {
+    pub kind: SceneLightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    #[serde(default)]
+    pub range: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SceneLightKind {
+    Directional,
+    Point,
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+impl From<&ComponentTransformDecomposed> for SceneTransformDecomposed {
+    fn from(component: &ComponentTransformDecomposed) -> Self {
+        Self {
+            translation: component.translation.0,
+            rotation: component.rotation.0,
+            scale: component.scale.0,
+        }
+    }
+}
+
+impl From<&SceneTransformDecomposed> for ComponentTransformDecomposed {
+    fn from(transform: &SceneTransformDecomposed) -> Self {
+        Self {
+            translation: Vec3(transform.translation),
+            rotation: Quaternion(transform.rotation),
+            scale: Vec3(transform.scale),
+        }
+    }
+}
+
+impl From<&ComponentLight> for SceneLight {
+    fn from(component: &ComponentLight) -> Self {
+        Self {
+            kind: match component.kind {
+                LightKind::Directional => SceneLightKind::Directional,
+                LightKind::Point => SceneLightKind::Point,
+                LightKind::Spot { inner_cone_angle, outer_cone_angle } =>
+                    SceneLightKind::Spot { inner_cone_angle, outer_cone_angle },
+            },
+            color: component.color.0,
+            intensity: component.intensity,
+            range: component.range,
+        }
+    }
+}
+
+impl From<&SceneLight> for ComponentLight {
+    fn from(light: &SceneLight) -> Self {
+        Self {
+            kind: match light.kind {
+                SceneLightKind::Directional => LightKind::Directional,
+                SceneLightKind::Point => LightKind::Point,
+                SceneLightKind::Spot { inner_cone_angle, outer_cone_angle } =>
+                    LightKind::Spot { inner_cone_angle, outer_cone_angle },
+            },
+            color: Vec3(light.color),
+            intensity: light.intensity,
+            range: light.range,
+        }
+    }
+}
+
+/// The directory the model bytes of the scene at `path` are stored in.
+pub fn models_directory(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name()
+        .map(|file_name| file_name.to_os_string())
+        .unwrap_or_default();
+
+    file_name.push(".models");
+
+    path.with_file_name(file_name)
+}
+
+/// Orders the entities so that each parent precedes its children, starting with `root`.
+/// Entities without a parent among `entities` are ordered by their id. A parent that would
+/// follow its child, because they form a cycle, is left for the caller to drop.
+fn topological_order(root: Entity, entities: &HashSet<Entity>, parents: &ReadStorage<ComponentParent>) -> Vec<Entity> {
+    let mut sorted: Vec<Entity> = entities.iter()
+        .filter(|entity| **entity != root)
+        .cloned()
+        .collect();
+
+    sorted.sort_by_key(|entity| entity.id());
+    sorted.insert(0, root);
+
+    let mut order = Vec::with_capacity(sorted.len());
+    let mut placed = HashSet::with_capacity(sorted.len());
+
+    for entity in sorted {
+        // The ancestors of the entity that have not been placed yet, nearest first.
+        let mut chain = Vec::new();
+        let mut current = entity;
+
+        while entities.contains(&current) && !placed.contains(&current) && !chain.contains(&current) {
+            chain.push(current);
+
+            match parents.get(current) {
+                Some(parent) => current = parent.entity,
+                None => break,
+            }
+        }
+
+        for entity in chain.into_iter().rev() {
+            placed.insert(entity);
+            order.push(entity);
+        }
+    }
+
+    order
+}
+
+impl Metaview {
+    /// Saves the entities of the loaded mapps, along with the models they use.
+    /// The models must have been loaded from a file or while a source directory was set,
+    /// see `ModelCache::set_source_directory`.
+    pub fn save_scene(&self, path: impl AsRef<Path>) -> Result<(), SceneError> {
+        let path = path.as_ref();
+        let models_directory = models_directory(path);
+        let entities_res = self.world.entities();
+        let parents = self.world.read_storage::<ComponentParent>();
+        let transforms = self.world.read_storage::<ComponentTransformRelative>();
+        let transforms_decomposed = self.world.read_storage::<ComponentTransformDecomposed>();
+        let models = self.world.read_storage::<ComponentModel>();
+        let visible = self.world.read_storage::<ComponentVisible>();
+        let lights = self.world.read_storage::<ComponentLight>();
+        let layers = self.world.read_storage::<ComponentLayers>();
+        let names = self.world.read_storage::<ComponentName>();
+        let tags = self.world.read_storage::<ComponentTags>();
+        let mut scene = Scene { mapps: Vec::with_capacity(self.mappcs.len()) };
+
+        std::fs::create_dir_all(&models_directory)
+            .map_err(|error| SceneError::Io { path: models_directory.clone(), error })?;
+
+        for mappc in &self.mappcs {
+            // Entities the mapp has detached from its subtree are saved as well.
+            let owned: HashSet<Entity> = mappc.entity_handles.owned_entities()
+                .filter(|entity| entities_res.is_alive(*entity))
+                .collect();
+            let entities = topological_order(mappc.root_entity, &owned, &parents);
+            let indices: HashMap<Entity, usize> = entities.iter().enumerate()
+                .map(|(index, entity)| (*entity, index))
+                .collect();
+            let mut model_indices: HashMap<ModelHash, usize> = HashMap::new();
+            let mut scene_mapp = SceneMapp {
+                name: mappc.name().to_string(),
+                models: Vec::new(),
+                entities: Vec::with_capacity(entities.len()),
+            };
+
+            for (index, entity) in entities.iter().enumerate() {
+                let model = match models.get(*entity) {
+                    Some(component) => match self.model_cache.source(&component.model) {
+                        Some((hash, source_path)) => {
+                            if let Some(index) = model_indices.get(&hash) {
+                                Some(*index)
+                            } else {
+                                let hex = hash_to_hex(&hash);
+                                let model_path = models_directory.join(&hex);
+
+                                if !model_path.exists() {
+                                    std::fs::copy(&source_path, &model_path)
+                                        .map_err(|error| SceneError::Io { path: source_path, error })?;
+                                }
+
+                                model_indices.insert(hash, scene_mapp.models.len());
+                                scene_mapp.models.push(hex);
+
+                                Some(scene_mapp.models.len() - 1)
+                            }
+                        },
+                        None => {
+                            eprintln!("The model of an entity of mapp {} is not stored by the model cache and cannot be saved.", mappc.name());
+                            None
+                        },
+                    },
+                    None => None,
+                };
+                let parent = parents.get(*entity)
+                    .and_then(|parent| indices.get(&parent.entity).cloned());
+
+                if parent.map(|parent| parent >= index).unwrap_or(false) {
+                    eprintln!("Entity #{} of mapp {} is part of a parent cycle, saving it without a parent.", index, mappc.name());
+                }
+
+                scene_mapp.entities.push(SceneEntity {
+                    parent: parent.filter(|parent| *parent < index),
+                    transform: transforms.get(*entity)
+                        .map(|transform| transform.matrix.clone()),
+                    transform_decomposed: transforms_decomposed.get(*entity)
+                        .map(SceneTransformDecomposed::from),
+                    model,
+                    visible: visible.get(*entity)
+                        .map(|visible| visible.visible),
+                    light: lights.get(*entity)
+                        .map(SceneLight::from),
+                    layers: layers.get(*entity)
+                        .map(|layers| layers.mask),
+                    name: names.get(*entity)
+                        .map(|name| name.name.clone()),
+                    tags: tags.get(*entity)
+                        .map(|tags| {
+                            let mut tags: Vec<String> = tags.tags.iter().cloned().collect();
+                            tags.sort();
+                            tags
+                        })
+                        .unwrap_or_default(),
+                });
+            }
+
+            // The root entity is always attached to the scene root.
+            if let Some(root) = scene_mapp.entities.first_mut() {
+                root.parent = None;
+            }
+
+            scene.mapps.push(scene_mapp);
+        }
+
+        let source = json5::to_string(&scene)
+            .map_err(|error| SceneError::Serialize { path: path.to_path_buf(), error })?;
+
+        std::fs::write(path, source)
+            .map_err(|error| SceneError::Io { path: path.to_path_buf(), error })?;
+
+        println!("Scene saved to {}.", path.display());
+
+        Ok(())
+    }
+
+    /// Reads the scene saved by `save_scene`. Must be called before `load_mapps`, which
+    /// restores the entities of the mapps in the scene instead of instantiating them.
+    pub fn load_scene(&mut self, path: impl AsRef<Path>) -> Result<(), SceneError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| SceneError::Io { path: path.to_path_buf(), error })?;
+        let scene: Scene = json5::from_str(&source)
+            .map_err(|error| SceneError::Parse { path: path.to_path_buf(), error })?;
+
+        for scene_mapp in &scene.mapps {
+            scene_mapp.validate(path)?;
+        }
+
+        self.pending_scene = Some((path.to_path_buf(), scene));
+
+        Ok(())
+    }
+
+    /// Takes the entities of the mapp out of the scene loaded by `load_scene`, if any.
+    pub(crate) fn take_scene_mapp(&mut self, name: &str) -> Option<(PathBuf, SceneMapp)> {
+        let (path, scene) = self.pending_scene.as_mut()?;
+        let index = scene.mapps.iter().position(|scene_mapp| scene_mapp.name == name)?;
+
+        Some((path.clone(), scene.mapps.remove(index)))
+    }
+
+    /// Discards the scene loaded by `load_scene`, warning about the mapps that were not
+    /// loaded and whose entities were therefore not restored.
+    pub(crate) fn discard_scene(&mut self) {
+        if let Some((path, scene)) = self.pending_scene.take() {
+            for scene_mapp in scene.mapps {
+                eprintln!("Mapp {} of scene {} is not loaded, skipping its entities.", scene_mapp.name, path.display());
+            }
+        }
+    }
+
+    /// Restores the entities of a mapp taken from the scene at `path`, without instantiating
+    /// the mapp. Nothing is restored, if any of the models cannot be loaded.
+    pub(crate) fn restore_mapp(&mut self, path: &Path, manifest: MappManifest, scene_mapp: SceneMapp) -> Result<(), SceneError> {
+        let models_directory = models_directory(path);
+        let mut models = Vec::with_capacity(scene_mapp.models.len());
+
+        for hex in &scene_mapp.models {
+            let model = self.model_cache.load_file_blocking(&mut self.ammolite, &models_directory.join(hex))
+                .map_err(|message| SceneError::Model { path: path.to_path_buf(), hash: hex.clone(), message })?;
+
+            models.push(model);
+        }
+
+        let handle = self.allocate_mapp_handle();
+        let mut mappc = MappContainer::restored(handle, manifest, &mut self.world);
+
+        mappc.capabilities.extend(&self.default_capabilities);
+
+        for model in &models {
+            mappc.add_model(model.clone());
+        }
+
+        let mut entities: Vec<Entity> = Vec::with_capacity(scene_mapp.entities.len());
+
+        for index in 0..scene_mapp.entities.len() {
+            entities.push(if index == 0 {
+                mappc.root_entity
+            } else {
+                self.world.create_entity().build()
+            });
+        }
+
+        {
+            let mut parents = self.world.write_storage::<ComponentParent>();
+            let mut transforms = self.world.write_storage::<ComponentTransformRelative>();
+            let mut transforms_decomposed = self.world.write_storage::<ComponentTransformDecomposed>();
+            let mut model_components = self.world.write_storage::<ComponentModel>();
+            let mut visible = self.world.write_storage::<ComponentVisible>();
+            let mut lights = self.world.write_storage::<ComponentLight>();
+            let mut layers = self.world.write_storage::<ComponentLayers>();
+            let mut names = self.world.write_storage::<ComponentName>();
+            let mut tags = self.world.write_storage::<ComponentTags>();
+
+            for (entity, scene_entity) in entities.iter().zip(&scene_mapp.entities) {
+                if let Some(parent) = scene_entity.parent {
+                    parents.insert(*entity, ComponentParent {
+                        entity: entities[parent],
+                    }).expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(transform) = &scene_entity.transform {
+                    transforms.insert(*entity, ComponentTransformRelative {
+                        matrix: transform.clone(),
+                    }).expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(transform) = &scene_entity.transform_decomposed {
+                    transforms_decomposed.insert(*entity, ComponentTransformDecomposed::from(transform))
+                        .expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(model) = scene_entity.model {
+                    model_components.insert(*entity, ComponentModel {
+                        model: Arc::clone(&models[model].model),
+                        geometry: Arc::clone(&models[model].geometry),
+                    }).expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(value) = scene_entity.visible {
+                    visible.insert(*entity, ComponentVisible { visible: value })
+                        .expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(light) = &scene_entity.light {
+                    lights.insert(*entity, ComponentLight::from(light))
+                        .expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(mask) = scene_entity.layers {
+                    layers.insert(*entity, ComponentLayers { mask })
+                        .expect("An error occurred while inserting a component into storage.");
+                }
+
+                if let Some(name) = &scene_entity.name {
+                    names.insert(*entity, ComponentName { name: name.clone() })
+                        .expect("An error occurred while inserting a component into storage.");
+                }
+
+                if !scene_entity.tags.is_empty() {
+                    tags.insert(*entity, ComponentTags { tags: scene_entity.tags.iter().cloned().collect() })
+                        .expect("An error occurred while inserting a component into storage.");
+                }
+            }
+        }
+
+        for entity in &entities {
+            mappc.entity_handles.insert(*entity, true);
+        }
+
+        println!("Restored {} entities of mapp {}.", entities.len(), mappc.name());
+
+        self.mappcs.push(mappc);
+
+        Ok(())
+    }
+}
+
+impl SceneMapp {
+    fn validate(&self, path: &Path) -> Result<(), SceneError> {
+        for (index, entity) in self.entities.iter().enumerate() {
+            if let Some(parent) = entity.parent {
+                if index == 0 || parent == index || parent >= self.entities.len() {
+                    return Err(SceneError::InvalidParent { path: path.to_path_buf(), mapp: self.name.clone(), entity: index });
+                }
+            }
+
+            if let Some(model) = entity.model {
+                if model >= self.models.len() {
+                    return Err(SceneError::InvalidModel { path: path.to_path_buf(), mapp: self.name.clone(), entity: index });
+                }
+            }
+
+            if let Some(light) = &entity.light {
+                if !ComponentLight::from(light).is_valid() {
+                    return Err(SceneError::InvalidLight { path: path.to_path_buf(), mapp: self.name.clone(), entity: index });
+                }
+            }
+        }
+
+        if let Some(index) = self.find_parent_cycle() {
+            return Err(SceneError::ParentCycle { path: path.to_path_buf(), mapp: self.name.clone(), entity: index });
+        }
+
+        Ok(())
+    }
+
+    /// Returns an entity that is its own ancestor, if any. The parent indices must be in range.
+    fn find_parent_cycle(&self) -> Option<usize> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Visit {
+            Unvisited,
+            InProgress,
+            Done,
+        }
+
+        let mut visits = vec![Visit::Unvisited; self.entities.len()];
+
+        for start in 0..self.entities.len() {
+            let mut path = Vec::new();
+            let mut current = Some(start);
+
+            while let Some(index) = current {
+                match visits[index] {
+                    Visit::Done => break,
+                    Visit::InProgress => return Some(index),
+                    Visit::Unvisited => {
+                        visits[index] = Visit::InProgress;
+                        path.push(index);
+                        current = self.entities[index].parent;
+                    },
+                }
+            }
+
+            for index in path {
+                visits[index] = Visit::Done;
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: json5::Error },
+    Serialize { path: PathBuf, error: json5::Error },
+    InvalidParent { path: PathBuf, mapp: String, entity: usize },
+    /// The entity is its own ancestor.
+    ParentCycle { path: PathBuf, mapp: String, entity: usize },
+    InvalidModel { path: PathBuf, mapp: String, entity: usize },
+    /// The light does not satisfy `ComponentLight::is_valid`.
+    InvalidLight { path: PathBuf, mapp: String, entity: usize },
+    Model { path: PathBuf, hash: String, message: String },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error } =>
+                write!(f, "{}: {}", path.display(), error),
+            SceneError::Parse { path, error } =>
+                write!(f, "{}: could not parse the scene: {}", path.display(), error),
+            SceneError::Serialize { path, error } =>
+                write!(f, "{}: could not serialize the scene: {}", path.display(), error),
+            SceneError::InvalidParent { path, mapp, entity } =>
+                write!(f, "{}: entity #{} of mapp {} has an invalid parent", path.display(), entity, mapp),
+            SceneError::ParentCycle { path, mapp, entity } =>
+                write!(f, "{}: entity #{} of mapp {} is its own ancestor", path.display(), entity, mapp),
+            SceneError::InvalidModel { path, mapp, entity } =>
+                write!(f, "{}: entity #{} of mapp {} has an invalid model", path.display(), entity, mapp),
+            SceneError::InvalidLight { path, mapp, entity } =>
+                write!(f, "{}: entity #{} of mapp {} has an invalid light", path.display(), entity, mapp),
+            SceneError::Model { path, hash, message } =>
+                write!(f, "{}: could not load the model {}: {}", path.display(), hash, message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[cfg(test)]
+mod tests {
+    use specs::{World, WorldExt, Builder};
+    use super::*;
+
+    fn scene_mapp(parents: &[Option<usize>]) -> SceneMapp {
+        SceneMapp {
+            name: "test-mapp".to_string(),
+            models: Vec::new(),
+            entities: parents.iter()
+                .map(|parent| SceneEntity {
+                    parent: *parent,
+                    ..SceneEntity::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn validate_accepts_trees_and_detached_entities() {
+        let path = Path::new("scene.json5");
+
+        assert!(scene_mapp(&[None, Some(0), Some(1), Some(0), None, Some(4)]).validate(path).is_ok());
+        // Children may precede their parents in hand-written scenes.
+        assert!(scene_mapp(&[None, Some(2), Some(0)]).validate(path).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_parents() {
+        let path = Path::new("scene.json5");
+
+        match scene_mapp(&[Some(1), None]).validate(path) {
+            Err(SceneError::InvalidParent { entity: 0, .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        match scene_mapp(&[None, Some(1)]).validate(path) {
+            Err(SceneError::InvalidParent { entity: 1, .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        match scene_mapp(&[None, Some(5)]).validate(path) {
+            Err(SceneError::InvalidParent { entity: 1, .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_parent_cycles() {
+        let path = Path::new("scene.json5");
+
+        match scene_mapp(&[None, Some(0), Some(3), Some(4), Some(2)]).validate(path) {
+            Err(SceneError::ParentCycle { .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        match scene_mapp(&[None, Some(2), Some(1)]).validate(path) {
+            Err(SceneError::ParentCycle { .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_invalid_models_and_lights() {
+        let path = Path::new("scene.json5");
+        let mut mapp = scene_mapp(&[None, Some(0)]);
+
+        mapp.entities[1].model = Some(0);
+
+        match mapp.validate(path) {
+            Err(SceneError::InvalidModel { entity: 1, .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        mapp.entities[1].model = None;
+        mapp.entities[1].light = Some(SceneLight {
+            kind: SceneLightKind::Spot { inner_cone_angle: 0.5, outer_cone_angle: 0.2 },
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            range: None,
+        });
+
+        match mapp.validate(path) {
+            Err(SceneError::InvalidLight { entity: 1, .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parents_precede_their_children() {
+        let mut world = World::new();
+
+        world.register::<ComponentParent>();
+
+        let scene_root = world.create_entity().build();
+        let root = world.create_entity().with(ComponentParent { entity: scene_root }).build();
+        // Created before their parents, so that ordering by id alone would not suffice.
+        let grandchild = world.create_entity().build();
+        let detached_child = world.create_entity().build();
+        let child = world.create_entity().with(ComponentParent { entity: root }).build();
+        let detached = world.create_entity().build();
+
+        {
+            let mut parents = world.write_storage::<ComponentParent>();
+
+            parents.insert(grandchild, ComponentParent { entity: child }).unwrap();
+            parents.insert(detached_child, ComponentParent { entity: detached }).unwrap();
+        }
+
+        let entities: HashSet<Entity> = [root, grandchild, detached_child, child, detached].iter().cloned().collect();
+        let order = topological_order(root, &entities, &world.read_storage::<ComponentParent>());
+
+        assert_eq!(order, vec![root, child, grandchild, detached, detached_child]);
+    }
+
+    #[test]
+    fn cycles_are_ordered_without_repetition() {
+        let mut world = World::new();
+
+        world.register::<ComponentParent>();
+
+        let root = world.create_entity().build();
+        let a = world.create_entity().build();
+        let b = world.create_entity().with(ComponentParent { entity: a }).build();
+
+        world.write_storage::<ComponentParent>().insert(a, ComponentParent { entity: b }).unwrap();
+
+        let entities: HashSet<Entity> = [root, a, b].iter().cloned().collect();
+        let order = topological_order(root, &entities, &world.read_storage::<ComponentParent>());
+
+        assert_eq!(order, vec![root, b, a]);
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use ::mlib::{CommandKind, QueryScope};
+
+/// A privilege a mapp may request in its manifest, or be granted by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    /// Loading models into the host.
+    Models,
+    /// Creating entities and modifying the ones within the mapp's own subtree.
+    Entities,
+    /// Reading the pose and field of view of the views, which reveals the head pose.
+    ViewOrientation,
+    /// Casting rays and shapes against the whole scene, which reveals entities of other mapps.
+    RayTrace,
+    /// Sending messages to other mapps.
+    Messaging,
+    /// Attaching light sources to entities, which affects the lighting of the whole scene.
+    Lights,
+    /// Looking up entities by name or tag in the whole scene, rather than only among the
+    /// mapp's own entities.
+    SceneQuery,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Models,
+        Capability::Entities,
+        Capability::ViewOrientation,
+        Capability::RayTrace,
+        Capability::Messaging,
+        Capability::Lights,
+        Capability::SceneQuery,
+    ];
+
+    /// The capabilities the host grants to every mapp, regardless of its manifest.
+    pub const DEFAULT: [Capability; 2] = [
+        Capability::Models,
+        Capability::Entities,
+    ];
+
+    /// Returns the capability needed to issue the command, or `None`, if every mapp may issue it.
+    pub fn required_by(kind: &CommandKind) -> Option<Capability> {
+        match kind {
+            CommandKind::Exit => None,
+            CommandKind::ModelCreate { .. }
+            | CommandKind::ModelDelete { .. } => Some(Capability::Models),
+            CommandKind::EntityRootGet
+            | CommandKind::EntityCreate
+            | CommandKind::EntityDelete { .. }
+            | CommandKind::EntityParentSet { .. }
+            | CommandKind::EntityModelSet { .. }
+            | CommandKind::EntityTransformSet { .. }
+            | CommandKind::EntityTransformDecomposedSet { .. }
+            | CommandKind::EntityTransformDecomposedGet { .. }
+            | CommandKind::EntityVisibleSet { .. }
+            | CommandKind::EntityNameSet { .. }
+            | CommandKind::EntityTagsSet { .. }
+            | CommandKind::EntityAnimationSet { .. }
+            | CommandKind::EntityLayersSet { .. } => Some(Capability::Entities),
+            CommandKind::EntityFindByName { scope, .. }
+            | CommandKind::EntityListByTag { scope, .. } => match scope {
+                QueryScope::Own => Some(Capability::Entities),
+                QueryScope::Scene => Some(Capability::SceneQuery),
+            },
+            CommandKind::GetViewOrientation { .. } => Some(Capability::ViewOrientation),
+            CommandKind::RayTrace { .. }
+            | CommandKind::ShapeCast { .. }
+            | CommandKind::ShapeOverlap { .. } => Some(Capability::RayTrace),
+            CommandKind::MessageSend { .. } => Some(Capability::Messaging),
+            CommandKind::EntityLightSet { .. } => Some(Capability::Lights),
+        }
+    }
+}
+
+/// A short name of the command, suitable for logs.
+pub fn command_name(kind: &CommandKind) -> &'static str {
+    match kind {
+        CommandKind::Exit => "Exit",
+        CommandKind::ModelCreate { .. } => "ModelCreate",
+        CommandKind::ModelDelete { .. } => "ModelDelete",
+        CommandKind::EntityRootGet => "EntityRootGet",
+        CommandKind::EntityCreate => "EntityCreate",
+        CommandKind::EntityDelete { .. } => "EntityDelete",
+        CommandKind::EntityParentSet { .. } => "EntityParentSet",
+        CommandKind::EntityModelSet { .. } => "EntityModelSet",
+        CommandKind::EntityTransformSet { .. } => "EntityTransformSet",
+        CommandKind::EntityTransformDecomposedSet { .. } => "EntityTransformDecomposedSet",
+        CommandKind::EntityTransformDecomposedGet { .. } => "EntityTransformDecomposedGet",
+        CommandKind::EntityVisibleSet { .. } => "EntityVisibleSet",
+        CommandKind::EntityNameSet { .. } => "EntityNameSet",
+        CommandKind::EntityTagsSet { .. } => "EntityTagsSet",
+        CommandKind::EntityAnimationSet { .. } => "EntityAnimationSet",
+        CommandKind::EntityLayersSet { .. } => "EntityLayersSet",
+        CommandKind::EntityFindByName { .. } => "EntityFindByName",
+        CommandKind::EntityListByTag { .. } => "EntityListByTag",
+        CommandKind::EntityLightSet { .. } => "EntityLightSet",
+        CommandKind::GetViewOrientation { .. } => "GetViewOrientation",
+        CommandKind::RayTrace { .. } => "RayTrace",
+        CommandKind::ShapeCast { .. } => "ShapeCast",
+        CommandKind::ShapeOverlap { .. } => "ShapeOverlap",
+        CommandKind::MessageSend { .. } => "MessageSend",
+    }
+}
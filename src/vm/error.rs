@@ -0,0 +1,134 @@
+use std::fmt;
+use std::path::PathBuf;
+use ::mlib::{Entity, Model, MappRecipient};
+use crate::vm::capability::Capability;
+use crate::vm::manifest::ManifestError;
+
+#[derive(Debug)]
+pub enum MappLoadError {
+    /// No mapp could be loaded, because of the given errors, if any.
+    NoMapps { errors: Vec<MappLoadError> },
+    Manifest(ManifestError),
+    FileNotFound { path: PathBuf },
+    Io { path: PathBuf, error: std::io::Error },
+    /// The file is not a well-formed wasm module.
+    InvalidWasm { path: PathBuf, reason: String },
+    /// The module does not export a function the host calls.
+    MissingExport { path: PathBuf, export: &'static str },
+    /// The module exports a name the host binds to, but not as a function, most likely
+    /// because the mapp was built against an incompatible version of mlib.
+    AbiMismatch { path: PathBuf, export: &'static str },
+    /// The module could not be compiled, linked or instantiated.
+    Instantiation { path: PathBuf, reason: String },
+}
+
+impl fmt::Display for MappLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MappLoadError::NoMapps { errors } => {
+                if errors.is_empty() {
+                    return write!(f, "at least one metaview application must be specified");
+                }
+
+                write!(f, "none of the metaview applications could be loaded")?;
+
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+
+                Ok(())
+            },
+            MappLoadError::Manifest(error) =>
+                write!(f, "{}", error),
+            MappLoadError::FileNotFound { path } =>
+                write!(f, "{}: no such file", path.display()),
+            MappLoadError::Io { path, error } =>
+                write!(f, "{}: {}", path.display(), error),
+            MappLoadError::InvalidWasm { path, reason } =>
+                write!(f, "{}: not a valid wasm module: {}", path.display(), reason),
+            MappLoadError::MissingExport { path, export } =>
+                write!(f, "{}: the module does not export `{}`", path.display(), export),
+            MappLoadError::AbiMismatch { path, export } =>
+                write!(f, "{}: incompatible mapp ABI: `{}` is not exported as a function", path.display(), export),
+            MappLoadError::Instantiation { path, reason } =>
+                write!(f, "{}: could not instantiate the module: {}", path.display(), reason),
+        }
+    }
+}
+
+impl std::error::Error for MappLoadError {}
+
+impl From<ManifestError> for MappLoadError {
+    fn from(error: ManifestError) -> Self {
+        MappLoadError::Manifest(error)
+    }
+}
+
+/// The reason a command was rejected, sent to the mapp as `CommandResponseKind::Error`.
+#[derive(Debug)]
+pub enum CommandError {
+    MissingCapability(Capability),
+    /// The handle was never given to the mapp.
+    InvalidEntity(Entity),
+    /// The entity the handle referred to no longer exists.
+    StaleEntity(Entity),
+    /// The entity belongs to another mapp and may not be modified.
+    ForeignEntity(Entity),
+    InvalidModel(Model),
+    /// Animations are applied to `ComponentTransformDecomposed`, which the entity lacks.
+    MissingDecomposedTransform(Entity),
+    /// The keyframe times are negative or out of order.
+    InvalidAnimation,
+    /// The intensity is negative, the range is not positive or the inner cone of a spot light
+    /// is wider than its outer cone.
+    InvalidLight,
+    /// A coordinate of the shape is not finite, or its radius or half-extents are negative.
+    InvalidShape,
+    /// The direction of a shape cast is not finite, or its distance is not finite and
+    /// non-negative.
+    InvalidCast,
+    /// The mapp's root entity is removed only when the mapp is unloaded.
+    RootEntityDeletion,
+    /// The mapp's root entity stays a child of the scene root.
+    RootEntityReparenting,
+    /// The parent is the entity itself or one of its descendants.
+    ParentCycle,
+    UnknownRecipient(MappRecipient),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::MissingCapability(capability) =>
+                write!(f, "the {:?} capability has not been granted to this mapp", capability),
+            CommandError::InvalidEntity(entity) =>
+                write!(f, "invalid entity handle {:?}", entity),
+            CommandError::StaleEntity(entity) =>
+                write!(f, "the entity {:?} no longer exists", entity),
+            CommandError::ForeignEntity(entity) =>
+                write!(f, "the entity {:?} belongs to another mapp", entity),
+            CommandError::InvalidModel(model) =>
+                write!(f, "invalid model handle {:?}", model),
+            CommandError::MissingDecomposedTransform(entity) =>
+                write!(f, "the entity {:?} must have a decomposed transform to be animated", entity),
+            CommandError::InvalidAnimation =>
+                write!(f, "the keyframe times must be non-negative and in order"),
+            CommandError::InvalidLight =>
+                write!(f, "the intensity must be non-negative, the range positive and the cone angles must satisfy 0 <= inner <= outer"),
+            CommandError::InvalidShape =>
+                write!(f, "the coordinates of the shape must be finite and its radius and half-extents non-negative"),
+            CommandError::InvalidCast =>
+                write!(f, "the direction must be finite and the maximum distance finite and non-negative"),
+            CommandError::RootEntityDeletion =>
+                write!(f, "the root entity of a mapp cannot be deleted"),
+            CommandError::RootEntityReparenting =>
+                write!(f, "the root entity of a mapp cannot be reparented"),
+            CommandError::ParentCycle =>
+                write!(f, "an entity cannot be parented to itself or one of its descendants"),
+            CommandError::UnknownRecipient(recipient) =>
+                write!(f, "no mapp matching {:?} is loaded", recipient),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
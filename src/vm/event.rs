@@ -19,15 +19,25 @@ pub use mlib::event::*;
 pub struct EventDistributor {
     events: Receiver<Event>,
     sender_to_clone: Sender<Event>,
+    /// Events addressed to a single mapp, such as messages from other mapps.
+    /// A single queue is used, so that events are delivered in the order they were sent.
+    targeted_events: Receiver<(mlib::MappHandle, Event)>,
+    targeted_sender: Sender<(mlib::MappHandle, Event)>,
+    /// The handles of the loaded mapps, by their manifest names.
+    mapp_handles: HashMap<String, mlib::MappHandle>,
 }
 
 impl EventDistributor {
     pub fn new() -> Self {
         let (sender, receiver) = channel();
+        let (targeted_sender, targeted_receiver) = channel();
 
         Self {
             events: receiver,
             sender_to_clone: sender,
+            targeted_events: targeted_receiver,
+            targeted_sender,
+            mapp_handles: HashMap::new(),
         }
     }
 
@@ -35,10 +45,42 @@ impl EventDistributor {
         self.sender_to_clone.clone()
     }
 
-    pub fn distribute_events(&self, mappcs: &mut [MappContainer], ammolite: &mut Ammolite<MediumData>, world: &mut World, camera: &Rc<RefCell<PitchYawCamera3>>) {
+    pub fn register_mapp(&mut self, name: &str, handle: mlib::MappHandle) {
+        self.mapp_handles.insert(name.to_string(), handle);
+    }
+
+    pub fn unregister_mapp(&mut self, name: &str) {
+        self.mapp_handles.remove(name);
+    }
+
+    /// Finds the loaded mapp a message is addressed to.
+    pub fn resolve_recipient(&self, recipient: &mlib::MappRecipient) -> Option<mlib::MappHandle> {
+        match recipient {
+            mlib::MappRecipient::Name(name) => self.mapp_handles.get(name).cloned(),
+            mlib::MappRecipient::Handle(handle) => {
+                self.mapp_handles.values()
+                    .find(|registered_handle| *registered_handle == handle)
+                    .cloned()
+            },
+        }
+    }
+
+    /// Queues an event for a single mapp, to be delivered by the next `distribute_events`.
+    pub fn send_to(&self, recipient: mlib::MappHandle, event: Event) {
+        self.targeted_sender.send((recipient, event))
+            .expect("The receiver of targeted events is owned by the distributor.");
+    }
+
+    pub fn distribute_events(&self, mappcs: &mut [MappContainer]) {
         while let Ok(event) = self.events.try_recv() {
             for mappc in &mut mappcs[..] {
-                mappc.send_event(event.clone(), ammolite, world, camera);
+                mappc.send_event(event.clone());
+            }
+        }
+
+        while let Ok((recipient, event)) = self.targeted_events.try_recv() {
+            if let Some(mappc) = mappcs.iter_mut().find(|mappc| mappc.handle == recipient) {
+                mappc.send_event(event);
             }
         }
     }
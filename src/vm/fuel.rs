@@ -0,0 +1,674 @@
+//! Fuel metering of wasm modules, so that a mapp stuck in a loop can be interrupted.
+//!
+//! The wasmtime version the host bindings are generated for cannot interrupt a running module,
+//! so the module is instrumented before it is instantiated instead: a mutable `i64` global holds
+//! the remaining fuel, which is decremented on entry to every function and at the start of every
+//! loop iteration. Once it drops below zero, the module traps with an integer division by zero,
+//! see `is_fuel_exhausted`. Every exported function is wrapped in a function that refills the
+//! fuel before calling it, so the budget applies to each call of the host into the mapp.
+
+use std::collections::HashMap;
+use std::fmt;
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_START: u8 = 8;
+const SECTION_ELEMENT: u8 = 9;
+const SECTION_CODE: u8 = 10;
+const SECTION_DATA: u8 = 11;
+const SECTION_DATA_COUNT: u8 = 12;
+
+const EXTERNAL_FUNCTION: u8 = 0x00;
+const EXTERNAL_TABLE: u8 = 0x01;
+const EXTERNAL_MEMORY: u8 = 0x02;
+const EXTERNAL_GLOBAL: u8 = 0x03;
+
+const VALUE_TYPE_I64: u8 = 0x7e;
+
+const OPCODE_LOOP: u8 = 0x03;
+const OPCODE_IF: u8 = 0x04;
+const OPCODE_END: u8 = 0x0b;
+const OPCODE_CALL: u8 = 0x10;
+const OPCODE_DROP: u8 = 0x1a;
+const OPCODE_LOCAL_GET: u8 = 0x20;
+const OPCODE_GLOBAL_GET: u8 = 0x23;
+const OPCODE_GLOBAL_SET: u8 = 0x24;
+const OPCODE_I32_CONST: u8 = 0x41;
+const OPCODE_I64_CONST: u8 = 0x42;
+const OPCODE_I64_LT_S: u8 = 0x53;
+const OPCODE_I32_DIV_U: u8 = 0x6e;
+const OPCODE_I64_SUB: u8 = 0x7d;
+const BLOCK_TYPE_EMPTY: u8 = 0x40;
+
+/// The fuel a call into a mapp may consume by default. A unit of fuel is consumed by every
+/// function call and loop iteration, so this amounts to well over a second of computation.
+pub const DEFAULT_CALL_FUEL: u64 = 1_000_000_000;
+
+/// The trap raised by the instrumentation, as reported by wasmtime.
+const FUEL_EXHAUSTED_TRAP: &str = "integer divide by zero";
+
+/// The reason a module could not be instrumented.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstrumentationError {
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for InstrumentationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.reason, self.offset)
+    }
+}
+
+impl std::error::Error for InstrumentationError {}
+
+/// Returns whether the message of a trap was caused by the mapp running out of fuel.
+/// Rust checks divisors before dividing, so mapps compiled from Rust do not trap this way
+/// on their own.
+pub fn is_fuel_exhausted(trap_message: &str) -> bool {
+    trap_message.contains(FUEL_EXHAUSTED_TRAP)
+}
+
+/// Instruments the module, so that each call into it may run for at most `fuel` function
+/// entries and loop iterations. The module is expected to be valid, see `validate_module`.
+pub fn instrument(bytes: &[u8], fuel: u64) -> Result<Vec<u8>, InstrumentationError> {
+    let fuel = fuel.min(i64::max_value() as u64) as i64;
+    let mut reader = Reader::new(bytes);
+
+    if reader.read_bytes(8)? != b"\0asm\x01\0\0\0" {
+        return Err(reader.error("unsupported wasm header"));
+    }
+
+    let mut sections = Vec::new();
+
+    while !reader.is_empty() {
+        let id = reader.read_byte()?;
+        let length = reader.read_u32()? as usize;
+        let start = reader.position;
+
+        sections.push((id, start, reader.read_bytes(length)?));
+    }
+
+    let mut imported_functions = 0;
+    let mut imported_globals = 0;
+    let mut defined_globals = 0;
+    let mut function_types = Vec::new();
+
+    for &(id, start, content) in &sections {
+        let mut reader = Reader::at(content, start);
+
+        match id {
+            SECTION_IMPORT => {
+                for _ in 0..reader.read_u32()? {
+                    reader.read_name()?;
+                    reader.read_name()?;
+
+                    match reader.read_byte()? {
+                        EXTERNAL_FUNCTION => {
+                            reader.read_u32()?;
+                            imported_functions += 1;
+                        },
+                        EXTERNAL_TABLE => {
+                            reader.read_byte()?;
+                            reader.skip_limits()?;
+                        },
+                        EXTERNAL_MEMORY => reader.skip_limits()?,
+                        EXTERNAL_GLOBAL => {
+                            reader.read_bytes(2)?;
+                            imported_globals += 1;
+                        },
+                        _ => return Err(reader.error("unsupported import kind")),
+                    }
+                }
+            },
+            SECTION_FUNCTION => {
+                for _ in 0..reader.read_u32()? {
+                    function_types.push(reader.read_u32()?);
+                }
+            },
+            SECTION_GLOBAL => defined_globals = reader.read_u32()?,
+            _ => (),
+        }
+    }
+
+    let fuel_global = imported_globals + defined_globals;
+
+    // Each exported function is given a wrapper, appended after the defined functions, so the
+    // indices of the existing functions are kept.
+    let mut wrappers: Vec<u32> = Vec::new();
+    let mut wrapper_indices: HashMap<u32, u32> = HashMap::new();
+
+    for &(id, start, content) in &sections {
+        if id != SECTION_EXPORT {
+            continue;
+        }
+
+        let mut reader = Reader::at(content, start);
+
+        for _ in 0..reader.read_u32()? {
+            reader.read_name()?;
+
+            let kind = reader.read_byte()?;
+            let index = reader.read_u32()?;
+
+            if kind == EXTERNAL_FUNCTION && index >= imported_functions + function_types.len() as u32 {
+                return Err(reader.error("exported function index out of bounds"));
+            }
+
+            if kind == EXTERNAL_FUNCTION && index >= imported_functions && !wrapper_indices.contains_key(&index) {
+                let wrapper_index = imported_functions + function_types.len() as u32 + wrappers.len() as u32;
+
+                wrapper_indices.insert(index, wrapper_index);
+                wrappers.push(index);
+            }
+        }
+    }
+
+    let parameter_counts = parameter_counts(&sections)?;
+    let mut output = bytes[..8].to_vec();
+    let mut global_section_written = false;
+
+    for &(id, start, content) in &sections {
+        let mut reader = Reader::at(content, start);
+
+        // The global section is inserted in its place in the section order, if it is missing.
+        if !global_section_written && section_follows_globals(id) {
+            write_section(&mut output, SECTION_GLOBAL, &fuel_global_section(0, &[], fuel));
+            global_section_written = true;
+        }
+
+        match id {
+            SECTION_FUNCTION => {
+                let mut section = Vec::new();
+
+                write_u32(&mut section, (function_types.len() + wrappers.len()) as u32);
+
+                for &type_index in function_types.iter().chain(wrappers.iter().map(|&index| {
+                    &function_types[(index - imported_functions) as usize]
+                })) {
+                    write_u32(&mut section, type_index);
+                }
+
+                write_section(&mut output, id, &section);
+            },
+            SECTION_GLOBAL => {
+                let count = reader.read_u32()?;
+
+                write_section(&mut output, id, &fuel_global_section(count, reader.remaining(), fuel));
+                global_section_written = true;
+            },
+            SECTION_EXPORT => {
+                let mut section = Vec::new();
+                let count = reader.read_u32()?;
+
+                write_u32(&mut section, count);
+
+                for _ in 0..count {
+                    let name = reader.read_name()?;
+                    let kind = reader.read_byte()?;
+                    let mut index = reader.read_u32()?;
+
+                    if kind == EXTERNAL_FUNCTION {
+                        index = *wrapper_indices.get(&index).unwrap_or(&index);
+                    }
+
+                    write_u32(&mut section, name.len() as u32);
+                    section.extend_from_slice(name);
+                    section.push(kind);
+                    write_u32(&mut section, index);
+                }
+
+                write_section(&mut output, id, &section);
+            },
+            SECTION_CODE => {
+                let mut section = Vec::new();
+                let count = reader.read_u32()?;
+
+                write_u32(&mut section, count + wrappers.len() as u32);
+
+                for _ in 0..count {
+                    let length = reader.read_u32()? as usize;
+                    let body_start = reader.position;
+                    let body = instrument_body(Reader::at(reader.read_bytes(length)?, body_start), fuel_global)?;
+
+                    write_u32(&mut section, body.len() as u32);
+                    section.extend_from_slice(&body);
+                }
+
+                for &function_index in &wrappers {
+                    let type_index = function_types[(function_index - imported_functions) as usize];
+                    let parameter_count = *parameter_counts.get(type_index as usize)
+                        .ok_or_else(|| reader.error("function type index out of bounds"))?;
+                    let body = wrapper_body(function_index, parameter_count, fuel_global, fuel);
+
+                    write_u32(&mut section, body.len() as u32);
+                    section.extend_from_slice(&body);
+                }
+
+                write_section(&mut output, id, &section);
+            },
+            _ => write_section(&mut output, id, content),
+        }
+    }
+
+    if !global_section_written {
+        write_section(&mut output, SECTION_GLOBAL, &fuel_global_section(0, &[], fuel));
+    }
+
+    Ok(output)
+}
+
+/// Whether the known section `id` is placed after the global section.
+fn section_follows_globals(id: u8) -> bool {
+    match id {
+        SECTION_EXPORT | SECTION_START | SECTION_ELEMENT | SECTION_DATA_COUNT | SECTION_CODE | SECTION_DATA => true,
+        _ => false,
+    }
+}
+
+/// Returns the number of parameters of each function type.
+fn parameter_counts(sections: &[(u8, usize, &[u8])]) -> Result<Vec<u32>, InstrumentationError> {
+    let mut counts = Vec::new();
+
+    for &(id, start, content) in sections {
+        if id != SECTION_TYPE {
+            continue;
+        }
+
+        let mut reader = Reader::at(content, start);
+
+        for _ in 0..reader.read_u32()? {
+            if reader.read_byte()? != 0x60 {
+                return Err(reader.error("unsupported function type"));
+            }
+
+            let parameter_count = reader.read_u32()?;
+
+            reader.read_bytes(parameter_count as usize)?;
+
+            let result_count = reader.read_u32()?;
+
+            reader.read_bytes(result_count as usize)?;
+            counts.push(parameter_count);
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Appends the fuel global to the `count` globals encoded in `globals`.
+fn fuel_global_section(count: u32, globals: &[u8], fuel: i64) -> Vec<u8> {
+    let mut section = Vec::new();
+
+    write_u32(&mut section, count + 1);
+    section.extend_from_slice(globals);
+    // A mutable `i64` initialized with the fuel, so that the instantiation is metered as well
+    section.extend_from_slice(&[VALUE_TYPE_I64, 0x01, OPCODE_I64_CONST]);
+    write_i64(&mut section, fuel);
+    section.push(OPCODE_END);
+
+    section
+}
+
+/// Encodes a function refilling the fuel and passing its parameters on to `function_index`.
+fn wrapper_body(function_index: u32, parameter_count: u32, fuel_global: u32, fuel: i64) -> Vec<u8> {
+    // No locals
+    let mut body = vec![0x00, OPCODE_I64_CONST];
+
+    write_i64(&mut body, fuel);
+    body.push(OPCODE_GLOBAL_SET);
+    write_u32(&mut body, fuel_global);
+
+    for parameter in 0..parameter_count {
+        body.push(OPCODE_LOCAL_GET);
+        write_u32(&mut body, parameter);
+    }
+
+    body.push(OPCODE_CALL);
+    write_u32(&mut body, function_index);
+    body.push(OPCODE_END);
+
+    body
+}
+
+/// Encodes the instructions consuming a unit of fuel, which trap once it has run out.
+fn write_fuel_check(body: &mut Vec<u8>, fuel_global: u32) {
+    body.push(OPCODE_GLOBAL_GET);
+    write_u32(body, fuel_global);
+    body.extend_from_slice(&[OPCODE_I64_CONST, 0x01, OPCODE_I64_SUB, OPCODE_GLOBAL_SET]);
+    write_u32(body, fuel_global);
+    body.push(OPCODE_GLOBAL_GET);
+    write_u32(body, fuel_global);
+    body.extend_from_slice(&[
+        OPCODE_I64_CONST, 0x00, OPCODE_I64_LT_S,
+        OPCODE_IF, BLOCK_TYPE_EMPTY,
+        OPCODE_I32_CONST, 0x01, OPCODE_I32_CONST, 0x00, OPCODE_I32_DIV_U, OPCODE_DROP,
+        OPCODE_END,
+    ]);
+}
+
+/// Inserts a fuel check at the start of the function body and of every loop within it.
+fn instrument_body(mut reader: Reader, fuel_global: u32) -> Result<Vec<u8>, InstrumentationError> {
+    let body_start = reader.position;
+    let mut body = Vec::with_capacity(reader.bytes.len() + 32);
+
+    for _ in 0..reader.read_u32()? {
+        reader.read_u32()?;
+        reader.read_byte()?;
+    }
+
+    body.extend_from_slice(reader.consumed_since(body_start));
+    write_fuel_check(&mut body, fuel_global);
+
+    while !reader.is_empty() {
+        let instruction_start = reader.position;
+        let opcode = skip_instruction(&mut reader)?;
+
+        body.extend_from_slice(reader.consumed_since(instruction_start));
+
+        if opcode == OPCODE_LOOP {
+            write_fuel_check(&mut body, fuel_global);
+        }
+    }
+
+    Ok(body)
+}
+
+/// Skips an instruction along with its immediates, returning its opcode.
+fn skip_instruction(reader: &mut Reader) -> Result<u8, InstrumentationError> {
+    let opcode = reader.read_byte()?;
+
+    match opcode {
+        // unreachable, nop, else, end, return, drop, select, comparisons, arithmetic,
+        // conversions and sign extensions, ref.is_null
+        0x00 | 0x01 | 0x05 | 0x0b | 0x0f | 0x1a | 0x1b | 0x45..=0xc4 | 0xd1 => (),
+        // block, loop, if; the block type is either a single byte or a signed type index
+        0x02..=0x04 => reader.skip_leb()?,
+        // br, br_if, call, return_call, local.*, global.*, table.get, table.set, i32.const,
+        // i64.const, memory.size, memory.grow, ref.null, ref.func
+        0x0c | 0x0d | 0x10 | 0x12 | 0x20..=0x26 | 0x3f | 0x40 | 0x41 | 0x42 | 0xd0 | 0xd2 => reader.skip_leb()?,
+        // call_indirect, return_call_indirect, loads and stores
+        0x11 | 0x13 | 0x28..=0x3e => {
+            reader.skip_leb()?;
+            reader.skip_leb()?;
+        },
+        // br_table
+        0x0e => {
+            for _ in 0..reader.read_u32()? {
+                reader.skip_leb()?;
+            }
+
+            reader.skip_leb()?;
+        },
+        // select with types
+        0x1c => {
+            let count = reader.read_u32()?;
+
+            reader.read_bytes(count as usize)?;
+        },
+        // f32.const, f64.const
+        0x43 => { reader.read_bytes(4)?; },
+        0x44 => { reader.read_bytes(8)?; },
+        // Saturating truncations, bulk memory and table instructions
+        0xfc => {
+            let immediates = match reader.read_u32()? {
+                0..=7 => 0,
+                // data.drop, memory.fill, elem.drop, table.grow, table.size, table.fill
+                9 | 11 | 13 | 15 | 16 | 17 => 1,
+                // memory.init, memory.copy, table.init, table.copy
+                8 | 10 | 12 | 14 => 2,
+                _ => return Err(reader.error("unsupported instruction")),
+            };
+
+            for _ in 0..immediates {
+                reader.skip_leb()?;
+            }
+        },
+        // SIMD, threads, exceptions and any other proposals
+        _ => return Err(reader.error(&format!("unsupported opcode 0x{:02x}", opcode))),
+    }
+
+    Ok(opcode)
+}
+
+fn write_section(output: &mut Vec<u8>, id: u8, content: &[u8]) {
+    output.push(id);
+    write_u32(output, content.len() as u32);
+    output.extend_from_slice(content);
+}
+
+fn write_u32(output: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+
+        value >>= 7;
+
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+
+        output.push(byte | 0x80);
+    }
+}
+
+fn write_i64(output: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+
+        value >>= 7;
+
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            output.push(byte);
+            return;
+        }
+
+        output.push(byte | 0x80);
+    }
+}
+
+/// Reads the parts of a module, keeping track of the offset within the module for errors.
+pub(super) struct Reader<'a> {
+    bytes: &'a [u8],
+    /// The offset of `bytes` within the module.
+    start: usize,
+    /// The offset of the next byte within the module.
+    pub(super) position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn new(bytes: &'a [u8]) -> Self {
+        Self::at(bytes, 0)
+    }
+
+    pub(super) fn at(bytes: &'a [u8], start: usize) -> Self {
+        Self { bytes, start, position: start }
+    }
+
+    pub(super) fn error(&self, reason: &str) -> InstrumentationError {
+        InstrumentationError {
+            offset: self.position,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.position - self.start >= self.bytes.len()
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.position - self.start..]
+    }
+
+    fn consumed_since(&self, position: usize) -> &'a [u8] {
+        &self.bytes[position - self.start..self.position - self.start]
+    }
+
+    pub(super) fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], InstrumentationError> {
+        let offset = self.position - self.start;
+
+        if length > self.bytes.len() - offset {
+            return Err(self.error("unexpected end of the module"));
+        }
+
+        self.position += length;
+
+        Ok(&self.bytes[offset..offset + length])
+    }
+
+    pub(super) fn read_byte(&mut self) -> Result<u8, InstrumentationError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub(super) fn read_u32(&mut self) -> Result<u32, InstrumentationError> {
+        let mut value = 0u32;
+
+        for shift in (0..35).step_by(7) {
+            let byte = self.read_byte()?;
+
+            value |= ((byte & 0x7f) as u32).checked_shl(shift).unwrap_or(0);
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(self.error("invalid LEB128 integer"))
+    }
+
+    /// Skips a signed or unsigned LEB128 integer of up to 64 bits.
+    fn skip_leb(&mut self) -> Result<(), InstrumentationError> {
+        for _ in 0..10 {
+            if self.read_byte()? & 0x80 == 0 {
+                return Ok(());
+            }
+        }
+
+        Err(self.error("invalid LEB128 integer"))
+    }
+
+    pub(super) fn read_name(&mut self) -> Result<&'a [u8], InstrumentationError> {
+        let length = self.read_u32()?;
+
+        self.read_bytes(length as usize)
+    }
+
+    pub(super) fn skip_limits(&mut self) -> Result<(), InstrumentationError> {
+        let flags = self.read_u32()?;
+
+        self.skip_leb()?;
+
+        if flags & 0x01 != 0 {
+            self.skip_leb()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a module with a function `() -> ()` for each of the bodies, which exports the
+    /// first function as `run`.
+    fn module(bodies: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        let mut function_section = Vec::new();
+        let mut code_section = Vec::new();
+
+        write_u32(&mut function_section, bodies.len() as u32);
+        write_u32(&mut code_section, bodies.len() as u32);
+
+        for body in bodies {
+            function_section.push(0x00);
+            write_u32(&mut code_section, body.len() as u32);
+            code_section.extend_from_slice(body);
+        }
+
+        write_section(&mut bytes, SECTION_TYPE, &[0x01, 0x60, 0x00, 0x00]);
+        write_section(&mut bytes, SECTION_FUNCTION, &function_section);
+        write_section(&mut bytes, SECTION_EXPORT, &[0x01, 0x03, b'r', b'u', b'n', EXTERNAL_FUNCTION, 0x00]);
+        write_section(&mut bytes, SECTION_CODE, &code_section);
+
+        bytes
+    }
+
+    /// Returns the function indices of the exports and the function bodies of a module.
+    fn exports_and_bodies(bytes: &[u8]) -> (Vec<u32>, Vec<Vec<u8>>) {
+        let mut reader = Reader::new(bytes);
+        let mut exports = Vec::new();
+        let mut bodies = Vec::new();
+
+        reader.read_bytes(8).unwrap();
+
+        while !reader.is_empty() {
+            let id = reader.read_byte().unwrap();
+            let length = reader.read_u32().unwrap() as usize;
+            let mut section = Reader::new(reader.read_bytes(length).unwrap());
+
+            match id {
+                SECTION_EXPORT => for _ in 0..section.read_u32().unwrap() {
+                    section.read_name().unwrap();
+                    section.read_byte().unwrap();
+                    exports.push(section.read_u32().unwrap());
+                },
+                SECTION_CODE => for _ in 0..section.read_u32().unwrap() {
+                    bodies.push(section.read_name().unwrap().to_vec());
+                },
+                _ => (),
+            }
+        }
+
+        (exports, bodies)
+    }
+
+    fn fuel_checks(body: &[u8]) -> usize {
+        let mut fuel_check = Vec::new();
+
+        write_fuel_check(&mut fuel_check, 0);
+
+        body.windows(fuel_check.len()).filter(|window| *window == &fuel_check[..]).count()
+    }
+
+    #[test]
+    fn exported_functions_refill_the_fuel() {
+        let bytes = instrument(&module(&[&[0x00, OPCODE_END]]), 300).unwrap();
+        let (exports, bodies) = exports_and_bodies(&bytes);
+
+        assert_eq!(exports, vec![1]);
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[1], wrapper_body(0, 0, 0, 300));
+    }
+
+    #[test]
+    fn function_entries_and_loops_consume_fuel() {
+        let looping: &[u8] = &[0x00, OPCODE_LOOP, BLOCK_TYPE_EMPTY, 0x0c, 0x00, OPCODE_END, OPCODE_END];
+        let calling: &[u8] = &[0x01, 0x01, 0x7f, OPCODE_CALL, 0x00, OPCODE_END];
+        let bytes = instrument(&module(&[looping, calling]), 300).unwrap();
+        let (_, bodies) = exports_and_bodies(&bytes);
+
+        assert_eq!(fuel_checks(&bodies[0]), 2);
+        assert_eq!(fuel_checks(&bodies[1]), 1);
+        // The fuel is checked after the locals are declared
+        assert_eq!(&bodies[1][..3], &calling[..3]);
+        assert_eq!(&bodies[1][bodies[1].len() - 3..], &calling[3..]);
+    }
+
+    #[test]
+    fn unsupported_instructions_are_rejected() {
+        let simd: &[u8] = &[0x00, 0xfd, 0x0c, OPCODE_END];
+
+        assert!(instrument(&module(&[simd]), 300).is_err());
+        assert!(instrument(b"\0asm\x01\0\0\0\x0a\x05\x01", 300).is_err());
+    }
+
+    #[test]
+    fn fuel_exhaustion_is_told_apart_from_other_traps() {
+        assert!(is_fuel_exhausted("wasm trap: integer divide by zero, source location: @002a"));
+        assert!(!is_fuel_exhausted("wasm trap: unreachable, source location: @002a"));
+    }
+}
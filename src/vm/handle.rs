@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use specs::world::EntitiesRes;
+use ::mlib::Entity;
+use crate::vm::error::CommandError;
+
+struct HandleEntry {
+    entity: specs::Entity,
+    /// Whether the mapp may modify the entity, or only refer to it.
+    owned: bool,
+}
+
+/// Maps the opaque entity handles a mapp is given to `specs::Entity` values, including their
+/// generation, so that a stale handle cannot reach a recycled entity. Handles are scoped to
+/// a single mapp and are never reused.
+pub struct EntityHandleTable {
+    entries: HashMap<usize, HandleEntry>,
+    handles: HashMap<specs::Entity, usize>,
+    next_handle: usize,
+}
+
+impl EntityHandleTable {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// Returns the handle of the entity, registering it first, if necessary.
+    /// An entity registered as owned stays owned.
+    pub fn insert(&mut self, entity: specs::Entity, owned: bool) -> Entity {
+        if let Some(&handle) = self.handles.get(&entity) {
+            let entry = self.entries.get_mut(&handle)
+                .expect("The handle tables are out of sync.");
+
+            entry.owned |= owned;
+
+            return Entity(handle);
+        }
+
+        let handle = self.next_handle;
+
+        self.next_handle += 1;
+        self.entries.insert(handle, HandleEntry { entity, owned });
+        self.handles.insert(entity, handle);
+
+        Entity(handle)
+    }
+
+    /// Returns the handle of the entity, if it has been registered.
+    pub fn get(&self, entity: specs::Entity) -> Option<Entity> {
+        self.handles.get(&entity).map(|handle| Entity(*handle))
+    }
+
+    /// Returns the handle of the entity, if it is owned by the mapp.
+    pub fn owned_handle(&self, entity: specs::Entity) -> Option<Entity> {
+        self.handles.get(&entity)
+            .filter(|handle| self.entries[handle].owned)
+            .map(|handle| Entity(*handle))
+    }
+
+    pub fn remove(&mut self, entity: specs::Entity) {
+        if let Some(handle) = self.handles.remove(&entity) {
+            self.entries.remove(&handle);
+        }
+    }
+
+    /// Forgets the entities that no longer exist, such as those deleted along with the subtree
+    /// of an unloaded mapp. Their handles keep resolving to `CommandError::StaleEntity`.
+    pub fn prune(&mut self, entities: &EntitiesRes) {
+        let entries = &mut self.entries;
+
+        self.handles.retain(|entity, handle| {
+            let alive = entities.is_alive(*entity);
+
+            if !alive {
+                entries.remove(handle);
+            }
+
+            alive
+        });
+    }
+
+    /// The number of registered entities.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Resolves a handle the mapp may refer to.
+    pub fn resolve(&self, handle: Entity, entities: &EntitiesRes) -> Result<specs::Entity, CommandError> {
+        let entry = match self.entries.get(&handle.0) {
+            Some(entry) => entry,
+            // Handles are never reused, so a handle that was given out refers to a deleted entity.
+            None if handle.0 < self.next_handle => return Err(CommandError::StaleEntity(handle)),
+            None => return Err(CommandError::InvalidEntity(handle)),
+        };
+
+        if !entities.is_alive(entry.entity) {
+            return Err(CommandError::StaleEntity(handle));
+        }
+
+        Ok(entry.entity)
+    }
+
+    /// Resolves a handle the mapp may modify.
+    pub fn resolve_owned(&self, handle: Entity, entities: &EntitiesRes) -> Result<specs::Entity, CommandError> {
+        let entity = self.resolve(handle, entities)?;
+
+        if !self.entries[&handle.0].owned {
+            return Err(CommandError::ForeignEntity(handle));
+        }
+
+        Ok(entity)
+    }
+
+    /// The entities created by the mapp.
+    pub fn owned_entities<'a>(&'a self) -> impl Iterator<Item=specs::Entity> + 'a {
+        self.entries.values()
+            .filter(|entry| entry.owned)
+            .map(|entry| entry.entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{World, WorldExt, Builder};
+    use super::*;
+
+    #[test]
+    fn handles_are_stable_and_never_reused() {
+        let mut world = World::new();
+        let a = world.create_entity().build();
+        let b = world.create_entity().build();
+        let mut table = EntityHandleTable::new();
+        let handle_a = table.insert(a, true);
+        let handle_b = table.insert(b, false);
+
+        assert_ne!(handle_a.0, handle_b.0);
+        assert_eq!(table.insert(a, false).0, handle_a.0);
+        assert_eq!(table.get(b).map(|handle| handle.0), Some(handle_b.0));
+
+        table.remove(a);
+
+        assert_ne!(table.insert(a, true).0, handle_a.0);
+    }
+
+    #[test]
+    fn ownership_is_kept_and_enforced() {
+        let mut world = World::new();
+        let owned = world.create_entity().build();
+        let foreign = world.create_entity().build();
+        let mut table = EntityHandleTable::new();
+        let owned_handle = table.insert(owned, true);
+        let foreign_handle = table.insert(foreign, false);
+
+        // Referring to an owned entity again does not give up its ownership.
+        table.insert(owned, false);
+
+        let entities = world.entities();
+
+        assert_eq!(table.resolve_owned(owned_handle, &entities).unwrap(), owned);
+        assert_eq!(table.resolve(foreign_handle, &entities).unwrap(), foreign);
+        assert!(table.owned_handle(foreign).is_none());
+        assert_eq!(table.owned_entities().collect::<Vec<_>>(), vec![owned]);
+
+        match table.resolve_owned(foreign_handle, &entities) {
+            Err(CommandError::ForeignEntity(handle)) => assert_eq!(handle.0, foreign_handle.0),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn unknown_and_stale_handles_are_rejected() {
+        let mut world = World::new();
+        let entity = world.create_entity().build();
+        let mut table = EntityHandleTable::new();
+        let handle = table.insert(entity, true);
+
+        world.delete_entity(entity).unwrap();
+
+        let recycled = world.create_entity().build();
+
+        // The recycled entity has the same index, but a different generation.
+        assert_eq!(recycled.id(), entity.id());
+
+        let entities = world.entities();
+
+        match table.resolve(handle, &entities) {
+            Err(CommandError::StaleEntity(stale)) => assert_eq!(stale.0, handle.0),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        match table.resolve(Entity(handle.0 + 1), &entities) {
+            Err(CommandError::InvalidEntity(_)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn prune_forgets_deleted_entities() {
+        let mut world = World::new();
+        let kept = world.create_entity().build();
+        let deleted = world.create_entity().build();
+        let mut table = EntityHandleTable::new();
+        let kept_handle = table.insert(kept, true);
+        let deleted_handle = table.insert(deleted, true);
+
+        world.delete_entity(deleted).unwrap();
+
+        let entities = world.entities();
+
+        table.prune(&entities);
+
+        assert_eq!(table.len(), 1);
+        assert!(table.get(deleted).is_none());
+        assert_eq!(table.owned_entities().collect::<Vec<_>>(), vec![kept]);
+        assert_eq!(table.resolve(kept_handle, &entities).unwrap(), kept);
+
+        match table.resolve(deleted_handle, &entities) {
+            Err(CommandError::StaleEntity(_)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
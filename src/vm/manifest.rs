@@ -0,0 +1,388 @@
+//! Mapps are described by a json5 manifest, such as:
+//!
+//! ```json5
+//! {
+//!     name: "example-mapp",
+//!     version: "0.1.0",
+//!     entry: "pkg/example_mapp.wasm",
+//!     assets: [ "assets/cube.glb" ],
+//!     capabilities: [ "models", "entities", "ray-trace", "messaging" ],
+//! }
+//! ```
+//!
+//! Relative paths are resolved against the directory containing the manifest.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::vm::capability::Capability;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappManifest {
+    /// The display name, which must be unique among the loaded mapps.
+    pub name: String,
+    /// A version of the form `MAJOR.MINOR.PATCH`.
+    pub version: String,
+    /// The path to the wasm module.
+    pub entry: PathBuf,
+    #[serde(default)]
+    pub assets: Vec<PathBuf>,
+    #[serde(default)]
+    pub capabilities: HashSet<Capability>,
+    /// The directory relative paths are resolved against.
+    #[serde(skip)]
+    pub directory: PathBuf,
+}
+
+impl MappManifest {
+    /// Reads and validates the manifest at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ManifestError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| ManifestError::Io { path: path.to_path_buf(), error })?;
+        let mut manifest: Self = json5::from_str(&source)
+            .map_err(|error| ManifestError::Parse { path: path.to_path_buf(), error })?;
+
+        manifest.directory = path.parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        manifest.validate(path)?;
+
+        Ok(manifest)
+    }
+
+    /// Creates a manifest for a bare wasm module, named after the file.
+    pub fn from_wasm_path(path: impl AsRef<Path>) -> Result<Self, ManifestError> {
+        let path = path.as_ref();
+        let manifest = Self {
+            name: path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            version: "0.0.0".to_string(),
+            entry: path.to_path_buf(),
+            assets: Vec::new(),
+            capabilities: HashSet::new(),
+            directory: PathBuf::new(),
+        };
+
+        manifest.validate(path)?;
+
+        Ok(manifest)
+    }
+
+    /// Loads a manifest from a `.json5` file, or synthesizes one for a bare `.wasm` module.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ManifestError> {
+        let path = path.as_ref();
+
+        if path.extension().map(|extension| extension == "wasm").unwrap_or(false) {
+            Self::from_wasm_path(path)
+        } else {
+            Self::load(path)
+        }
+    }
+
+    /// Creates a manifest for a mapp compiled into the host.
+    pub fn native(name: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            entry: PathBuf::new(),
+            assets: Vec::new(),
+            capabilities: HashSet::new(),
+            directory: PathBuf::new(),
+        }
+    }
+
+    pub fn entry_path(&self) -> PathBuf {
+        self.directory.join(&self.entry)
+    }
+
+    pub fn asset_paths<'a>(&'a self) -> impl Iterator<Item=PathBuf> + 'a {
+        self.assets.iter().map(move |asset| self.directory.join(asset))
+    }
+
+    fn validate(&self, path: &Path) -> Result<(), ManifestError> {
+        if self.name.trim().is_empty() {
+            return Err(ManifestError::EmptyName { path: path.to_path_buf() });
+        }
+
+        if parse_version(&self.version).is_none() {
+            return Err(ManifestError::InvalidVersion {
+                path: path.to_path_buf(),
+                version: self.version.clone(),
+            });
+        }
+
+        let entry_path = self.entry_path();
+
+        if entry_path.extension().map(|extension| extension != "wasm").unwrap_or(true) {
+            return Err(ManifestError::InvalidEntry { path: path.to_path_buf(), entry: entry_path });
+        }
+
+        if !entry_path.is_file() {
+            return Err(ManifestError::MissingEntry { path: path.to_path_buf(), entry: entry_path });
+        }
+
+        for asset_path in self.asset_paths() {
+            if !asset_path.is_file() {
+                return Err(ManifestError::MissingAsset { path: path.to_path_buf(), asset: asset_path });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that none of the other manifests has the same name.
+    pub fn validate_unique_name<'a>(&self, path: &Path, others: impl IntoIterator<Item=&'a MappManifest>) -> Result<(), ManifestError> {
+        if others.into_iter().any(|other| other.name == self.name) {
+            return Err(ManifestError::DuplicateName {
+                path: path.to_path_buf(),
+                name: self.name.clone(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a version of the form `MAJOR.MINOR.PATCH`.
+pub fn parse_version(version: &str) -> Option<(u16, u16, u16)> {
+    let mut parts = version.split('.').map(str::parse::<u16>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = parts.next()?.ok()?;
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some((major, minor, patch))
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: json5::Error },
+    EmptyName { path: PathBuf },
+    InvalidVersion { path: PathBuf, version: String },
+    InvalidEntry { path: PathBuf, entry: PathBuf },
+    MissingEntry { path: PathBuf, entry: PathBuf },
+    MissingAsset { path: PathBuf, asset: PathBuf },
+    DuplicateName { path: PathBuf, name: String },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ManifestError::Io { path, error } =>
+                write!(f, "{}: could not read the manifest: {}", path.display(), error),
+            ManifestError::Parse { path, error } =>
+                write!(f, "{}: could not parse the manifest: {}", path.display(), error),
+            ManifestError::EmptyName { path } =>
+                write!(f, "{}: the mapp name must not be empty", path.display()),
+            ManifestError::InvalidVersion { path, version } =>
+                write!(f, "{}: invalid version {:?}, expected MAJOR.MINOR.PATCH", path.display(), version),
+            ManifestError::InvalidEntry { path, entry } =>
+                write!(f, "{}: the entry module {} is not a .wasm file", path.display(), entry.display()),
+            ManifestError::MissingEntry { path, entry } =>
+                write!(f, "{}: the entry module {} does not exist", path.display(), entry.display()),
+            ManifestError::MissingAsset { path, asset } =>
+                write!(f, "{}: the asset {} does not exist", path.display(), asset.display()),
+            ManifestError::DuplicateName { path, name } =>
+                write!(f, "{}: a mapp named {:?} is already loaded", path.display(), name),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory, unique to the test, to write manifests and modules into.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir()
+            .join(format!("metaview-manifest-{}-{}", std::process::id(), name));
+
+        if directory.exists() {
+            std::fs::remove_dir_all(&directory).unwrap();
+        }
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        directory
+    }
+
+    /// Writes the manifest, along with the module and the asset it refers to.
+    fn write_manifest(directory: &Path, source: &str) -> PathBuf {
+        let path = directory.join("mapp.json5");
+
+        std::fs::write(directory.join("mapp.wasm"), b"").unwrap();
+        std::fs::write(directory.join("cube.glb"), b"").unwrap();
+        std::fs::write(&path, source).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn parse_version_accepts_major_minor_patch() {
+        assert_eq!(parse_version("0.1.0"), Some((0, 1, 0)));
+        assert_eq!(parse_version("12.34.65535"), Some((12, 34, 65535)));
+    }
+
+    #[test]
+    fn parse_version_rejects_other_forms() {
+        for version in &["", "1", "1.2", "1.2.3.4", "1.2.x", "1..3", "-1.2.3", "1.2.65536", " 1.2.3"] {
+            assert_eq!(parse_version(version), None, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn load_resolves_paths_against_the_manifest() {
+        let directory = test_directory("valid");
+        let path = write_manifest(&directory, r#"{
+            name: "example-mapp",
+            version: "0.1.0",
+            entry: "mapp.wasm",
+            assets: [ "cube.glb" ],
+            capabilities: [ "ray-trace", "messaging" ],
+        }"#);
+        let manifest = MappManifest::load(&path).unwrap();
+
+        assert_eq!(manifest.name, "example-mapp");
+        assert_eq!(manifest.version, "0.1.0");
+        assert_eq!(manifest.entry_path(), directory.join("mapp.wasm"));
+        assert_eq!(manifest.asset_paths().collect::<Vec<_>>(), vec![directory.join("cube.glb")]);
+        assert_eq!(manifest.capabilities, [Capability::RayTrace, Capability::Messaging].iter().cloned().collect());
+    }
+
+    #[test]
+    fn optional_fields_default_to_empty() {
+        let directory = test_directory("minimal");
+        let path = write_manifest(&directory, r#"{ name: "minimal", version: "1.0.0", entry: "mapp.wasm" }"#);
+        let manifest = MappManifest::from_path(&path).unwrap();
+
+        assert!(manifest.assets.is_empty());
+        assert!(manifest.capabilities.is_empty());
+    }
+
+    #[test]
+    fn bare_modules_are_named_after_the_file() {
+        let directory = test_directory("bare");
+        let path = directory.join("bare_mapp.wasm");
+
+        std::fs::write(&path, b"").unwrap();
+
+        let manifest = MappManifest::from_path(&path).unwrap();
+
+        assert_eq!(manifest.name, "bare_mapp");
+        assert_eq!(manifest.entry_path(), path);
+    }
+
+    #[test]
+    fn missing_manifest_is_an_io_error() {
+        let directory = test_directory("io");
+
+        match MappManifest::load(directory.join("missing.json5")) {
+            Err(ManifestError::Io { .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn malformed_manifests_are_parse_errors() {
+        let sources = [
+            r#"{ name: "mapp", version: "0.1.0", entry: "mapp.wasm""#,
+            r#"{ name: "mapp", version: "0.1.0", entry: "mapp.wasm", unknown: 1 }"#,
+            r#"{ name: "mapp", version: "0.1.0", entry: "mapp.wasm", capabilities: [ "everything" ] }"#,
+            r#"{ name: "mapp", version: "0.1.0" }"#,
+        ];
+
+        for (index, source) in sources.iter().enumerate() {
+            let directory = test_directory(&format!("parse-{}", index));
+
+            match MappManifest::load(write_manifest(&directory, source)) {
+                Err(ManifestError::Parse { .. }) => (),
+                result => panic!("unexpected result for {}: {:?}", source, result),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_names_are_rejected() {
+        let directory = test_directory("empty-name");
+        let path = write_manifest(&directory, r#"{ name: "  ", version: "0.1.0", entry: "mapp.wasm" }"#);
+
+        match MappManifest::load(&path) {
+            Err(ManifestError::EmptyName { .. }) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_versions_are_rejected() {
+        let directory = test_directory("invalid-version");
+        let path = write_manifest(&directory, r#"{ name: "mapp", version: "1.0", entry: "mapp.wasm" }"#);
+
+        match MappManifest::load(&path) {
+            Err(ManifestError::InvalidVersion { version, .. }) => assert_eq!(version, "1.0"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn entries_must_be_wasm_modules() {
+        let directory = test_directory("invalid-entry");
+        let path = write_manifest(&directory, r#"{ name: "mapp", version: "0.1.0", entry: "cube.glb" }"#);
+
+        match MappManifest::load(&path) {
+            Err(ManifestError::InvalidEntry { entry, .. }) => assert_eq!(entry, directory.join("cube.glb")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn entries_must_exist() {
+        let directory = test_directory("missing-entry");
+        let path = write_manifest(&directory, r#"{ name: "mapp", version: "0.1.0", entry: "other.wasm" }"#);
+
+        match MappManifest::load(&path) {
+            Err(ManifestError::MissingEntry { entry, .. }) => assert_eq!(entry, directory.join("other.wasm")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn assets_must_exist() {
+        let directory = test_directory("missing-asset");
+        let path = write_manifest(&directory, r#"{
+            name: "mapp",
+            version: "0.1.0",
+            entry: "mapp.wasm",
+            assets: [ "cube.glb", "sphere.glb" ],
+        }"#);
+
+        match MappManifest::load(&path) {
+            Err(ManifestError::MissingAsset { asset, .. }) => assert_eq!(asset, directory.join("sphere.glb")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn names_must_be_unique() {
+        let first = MappManifest::native("mapp", "0.1.0");
+        let second = MappManifest::native("other-mapp", "0.1.0");
+        let duplicate = MappManifest::native("mapp", "0.2.0");
+        let path = Path::new("duplicate.json5");
+
+        assert!(second.validate_unique_name(path, &[first.clone()]).is_ok());
+
+        match duplicate.validate_unique_name(path, &[first, second]) {
+            Err(ManifestError::DuplicateName { name, .. }) => assert_eq!(name, "mapp"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
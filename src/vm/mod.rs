@@ -1,32 +1,237 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Instant, Duration};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::time::Duration;
 use ammolite_math::*;
-use ammolite::{Ammolite, Ray, WorldSpaceModel};
+use ammolite::{Ammolite, Ray};
 use ammolite::camera::{Camera, PitchYawCamera3};
-use specs::{World, WorldExt, world::{Builder, EntitiesRes}};
+use specs::{Join, World, WorldExt, world::{Builder, EntitiesRes}};
 use serde::{Deserialize, Serialize};
 use json5::{from_str, to_string};
 use ::mlib::*;
 use crate::ecs::*;
+use crate::ecs::animation::{AnimationClip, ComponentAnimation, Easing, Keyframe};
+use crate::ecs::bvh::{Affine, Bvh, ResourceSpatialIndex, SpatialItem, TriangleSource};
+use crate::ecs::shape::{self, ShapeContact, Volume};
 use crate::medium::MediumData;
+use self::event::EventDistributor;
+use self::capability::{Capability, command_name};
+use self::error::{MappLoadError, CommandError};
+use self::handle::EntityHandleTable;
+use self::manifest::MappManifest;
+use self::model_cache::{ModelCache, ModelRequest, ModelHash, LoadedModel};
+use self::reload::FileWatcher;
+use self::symbols::FunctionSymbols;
+use self::worker::{MappWorker, MappRequest, MappReply, MappCrash};
 
 pub mod event;
+pub mod capability;
+pub mod error;
+pub mod fuel;
+pub mod handle;
+pub mod manifest;
+pub mod model_cache;
+pub mod reload;
+pub mod symbols;
+pub mod worker;
 
 #[mapp(host)]
 pub struct Mapp;
 pub struct NativeMapp;
 
+/// The functions of a wasm module the host binds to through `MappExports`.
+const REQUIRED_EXPORTS: &[&str] = &[
+    "update",
+    "send_command",
+    "receive_command_response",
+    "receive_event",
+    "flush_io",
+];
+
+/// The most commands of a mapp applied per frame, so that a mapp flooding the host with
+/// commands does not stall rendering. A mapp is not asked to update again until all of its
+/// commands have been applied.
+const MAX_COMMANDS_PER_FRAME: usize = 1024;
+
+/// Checks that the file is a well-formed wasm module exporting `REQUIRED_EXPORTS` as functions.
+/// Returns the contents of the file, so that they need not be read again.
+fn validate_module(path: &Path) -> Result<Vec<u8>, MappLoadError> {
+    use wasmparser::{ModuleReader, SectionCode, ExternalKind};
+
+    let bytes = std::fs::read(path).map_err(|error| {
+        if error.kind() == std::io::ErrorKind::NotFound {
+            MappLoadError::FileNotFound { path: path.to_path_buf() }
+        } else {
+            MappLoadError::Io { path: path.to_path_buf(), error }
+        }
+    })?;
+    let invalid_wasm = |error: wasmparser::BinaryReaderError| MappLoadError::InvalidWasm {
+        path: path.to_path_buf(),
+        reason: format!("{} at offset {}", error.message, error.offset),
+    };
+    let mut reader = ModuleReader::new(&bytes[..]).map_err(invalid_wasm)?;
+    let mut exports = HashMap::new();
+
+    while !reader.eof() {
+        let section = reader.read().map_err(invalid_wasm)?;
+
+        if let SectionCode::Export = section.code {
+            for export in section.get_export_section_reader().map_err(invalid_wasm)? {
+                let export = export.map_err(invalid_wasm)?;
+
+                exports.insert(export.field.to_string(), export.kind);
+            }
+        }
+    }
+
+    for &export in REQUIRED_EXPORTS {
+        match exports.get(export) {
+            Some(ExternalKind::Function) => (),
+            Some(_) => return Err(MappLoadError::AbiMismatch { path: path.to_path_buf(), export }),
+            None => return Err(MappLoadError::MissingExport { path: path.to_path_buf(), export }),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Instruments the module with a fuel budget for each call, see `fuel::instrument`, and writes it
+/// to a temporary file, as the bindings only load modules from files.
+/// Returns the path of the file along with the instrumented module.
+fn write_metered_module(path: &Path, bytes: &[u8], call_fuel: u64) -> Result<(PathBuf, Vec<u8>), MappLoadError> {
+    static NEXT_MODULE_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let bytes = fuel::instrument(bytes, call_fuel).map_err(|error| MappLoadError::InvalidWasm {
+        path: path.to_path_buf(),
+        reason: format!("the module could not be metered: {}", error),
+    })?;
+    let metered_path = std::env::temp_dir().join(format!(
+        "metaview-{}-{}.wasm", std::process::id(), NEXT_MODULE_ID.fetch_add(1, Ordering::Relaxed),
+    ));
+
+    std::fs::write(&metered_path, &bytes)
+        .map_err(|error| MappLoadError::Io { path: metered_path.clone(), error })?;
+
+    Ok((metered_path, bytes))
+}
+
+/// Checks that `entity` may be given the parent `parent_entity`: the root entity of a mapp keeps
+/// its parent, and an entity cannot become its own ancestor.
+fn validate_parent(
+    parents: &specs::ReadStorage<ComponentParent>,
+    entity: specs::Entity,
+    parent_entity: Option<specs::Entity>,
+    root_entity: specs::Entity,
+) -> Result<(), CommandError> {
+    if entity == root_entity {
+        return Err(CommandError::RootEntityReparenting);
+    }
+
+    match parent_entity {
+        Some(parent_entity) if is_in_subtree(parents, parent_entity, entity) => Err(CommandError::ParentCycle),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappState {
+    Running,
+    /// The mapp trapped or panicked; its worker thread has terminated.
+    Crashed,
+    /// A call into the mapp ran out of fuel, see `fuel`; its worker thread has terminated.
+    Unresponsive,
+    /// The entities of the mapp were restored from a scene, so it was not instantiated and
+    /// its entities are kept as they were saved.
+    Restored,
+}
+
+impl MappState {
+    /// Returns whether the worker thread of the mapp has terminated, because the mapp crashed
+    /// or ran out of fuel.
+    pub fn has_terminated(self) -> bool {
+        match self {
+            MappState::Crashed | MappState::Unresponsive => true,
+            MappState::Running | MappState::Restored => false,
+        }
+    }
+}
+
+/// What happens to the entities of a mapp that has crashed or become unresponsive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPolicy {
+    /// The entities are kept in the scene as they were at the time of the crash.
+    Freeze,
+    /// The mapp is unloaded along with its scene subtree.
+    Remove,
+}
+
+/// A ray's hit on the model of an entity.
+struct RayHit {
+    entity: specs::Entity,
+    distance: f32,
+    /// The world-space normal of the surface, facing the origin of the ray.
+    normal: [f32; 3],
+    /// The texture coordinates of the first set, if the primitive has any.
+    texcoord: Option<[f32; 2]>,
+    source: TriangleSource,
+}
+
 pub struct MappContainer {
-    pub mapp: Box<dyn MappInterface>,
-    pub models: Vec<Arc<ammolite::model::Model>>,
+    /// The worker running the mapp, or `None`, if the mapp was restored from a scene.
+    pub worker: Option<MappWorker>,
+    /// Identifies the mapp to other mapps; kept when the mapp is reloaded.
+    pub handle: MappHandle,
+    pub manifest: MappManifest,
+    /// The commands the mapp is allowed to issue, see `Capability::required_by`.
+    pub capabilities: HashSet<Capability>,
+    /// The models loaded by the mapp, indexed by their handles. Deleted models leave a `None`
+    /// behind, so that handles are never reused.
+    pub models: Vec<Option<LoadedModel>>,
+    /// The `ModelCreate` commands waiting for a model to be loaded in the background.
+    pub pending_models: HashMap<ModelHash, Vec<usize>>,
     pub root_entity: specs::Entity,
+    /// Translates the entity handles given to the mapp, see `EntityHandleTable`.
+    pub entity_handles: EntityHandleTable,
+    /// Set once the mapp has sent `CommandKind::Exit`; the host unloads it afterwards.
+    pub exit_requested: bool,
+    /// Watches the wasm module the mapp was loaded from, if any, for hot-reloading.
+    pub watcher: Option<FileWatcher>,
+    /// The functions of the wasm module the mapp was instantiated from, if any, with which
+    /// the locations of its traps are resolved.
+    pub symbols: Option<FunctionSymbols>,
+    pub state: MappState,
 }
 
 impl MappContainer {
-    pub fn new(mapp_interface: Box<dyn MappInterface>, world: &mut World) -> Self {
+    /// Constructs the mapp on a worker thread of its own, in the background, see
+    /// `MappWorker::spawn`. A mapp that cannot be constructed is treated as crashed.
+    pub fn new<E: fmt::Display + Send + 'static>(
+        factory: impl FnOnce() -> Result<Box<dyn MappInterface>, E> + Send + 'static,
+        handle: MappHandle,
+        manifest: MappManifest,
+        world: &mut World,
+    ) -> Self {
+        let worker = MappWorker::spawn(&manifest.name, factory);
+
+        Self::with_worker(Some(worker), handle, manifest, world)
+    }
+
+    /// Constructs a container for a mapp whose entities are restored from a scene, instead of
+    /// instantiating the mapp, see `Metaview::load_scene`.
+    pub fn restored(handle: MappHandle, manifest: MappManifest, world: &mut World) -> Self {
+        let mut mappc = Self::with_worker(None, handle, manifest, world);
+
+        mappc.state = MappState::Restored;
+
+        mappc
+    }
+
+    fn with_worker(worker: Option<MappWorker>, handle: MappHandle, manifest: MappManifest, world: &mut World) -> Self {
         let resource_scene_root = world.fetch::<ResourceSceneRoot>().0;
         let root_entity = world.create_entity()
             .with(ComponentParent {
@@ -36,30 +241,165 @@ impl MappContainer {
                 matrix: Mat4::IDENTITY,
             })
             .build();
+        let capabilities = manifest.capabilities.clone();
+        let mut entity_handles = EntityHandleTable::new();
+
+        entity_handles.insert(root_entity, true);
+
         Self {
-            mapp: mapp_interface,
+            worker,
+            handle,
+            manifest,
+            capabilities,
             models: Vec::new(),
+            pending_models: HashMap::new(),
             root_entity,
+            entity_handles,
+            exit_requested: false,
+            watcher: None,
+            symbols: None,
+            state: MappState::Running,
         }
     }
 
-    pub fn from_wasm(mapp: Mapp, world: &mut World) -> Self {
-        Self::new(Box::new(mapp), world)
+    /// Validates the manifest's entry module and instantiates it on a worker thread.
+    /// Only the validation is waited for, so an error of the instantiation is reported later,
+    /// as a crash of the mapp.
+    ///
+    /// Each call into the mapp may consume at most `call_fuel`, if given, after which the mapp
+    /// is interrupted and marked unresponsive, see `fuel`.
+    pub fn from_wasm(handle: MappHandle, manifest: MappManifest, call_fuel: Option<u64>, world: &mut World) -> Result<Self, MappLoadError> {
+        let entry_path = manifest.entry_path();
+        let bytes = validate_module(&entry_path)?;
+        // The traps are located in the module that is actually instantiated
+        let (metered_path, symbols) = match call_fuel {
+            Some(call_fuel) => {
+                let (metered_path, metered_bytes) = write_metered_module(&entry_path, &bytes, call_fuel)?;
+
+                (Some(metered_path), FunctionSymbols::parse(&metered_bytes))
+            },
+            None => (None, FunctionSymbols::parse(&bytes)),
+        };
+
+        let watcher = FileWatcher::new(&entry_path);
+        let mut mappc = Self::new(move || -> Result<Box<dyn MappInterface>, MappLoadError> {
+            let result = MappExports::load_file(metered_path.as_ref().unwrap_or(&entry_path));
+
+            if let Some(metered_path) = &metered_path {
+                std::fs::remove_file(metered_path).ok();
+            }
+
+            let mapp_exports = result
+                .map_err(|error| MappLoadError::Instantiation {
+                    path: entry_path.clone(),
+                    reason: error.to_string(),
+                })?;
+            let mapp: Box<dyn MappInterface> = Box::new(Mapp::initialize(mapp_exports));
+
+            Ok(mapp)
+        }, handle, manifest, world);
+
+        mappc.watcher = Some(watcher);
+        mappc.symbols = Some(symbols);
+
+        Ok(mappc)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.manifest.name
+    }
+
+    fn send(&mut self, request: MappRequest) {
+        if self.state == MappState::Running && !self.exit_requested {
+            if let Some(worker) = self.worker.as_mut() {
+                worker.send(request);
+            }
+        }
     }
 
-    pub fn send_event(&mut self, event: Event, ammolite: &mut Ammolite<MediumData>, world: &mut World, camera: &Rc<RefCell<PitchYawCamera3>>) {
-        self.mapp.receive_event(event);
-        self.process_commands(ammolite, world, camera, true);
+    fn crash(&mut self, mut crash: MappCrash) {
+        if crash.frames.is_empty() {
+            crash.frames.extend(self.symbols.as_ref().and_then(|symbols| symbols.resolve_trap(&crash.message)));
+        }
+
+        if fuel::is_fuel_exhausted(&crash.message) {
+            eprintln!("Mapp {} ran out of fuel and is unresponsive: {}", self.name(), crash);
+            self.state = MappState::Unresponsive;
+        } else {
+            eprintln!("Mapp {} crashed: {}", self.name(), crash);
+            self.state = MappState::Crashed;
+        }
     }
 
+    /// Asks the mapp to update, unless it is still busy handling the previous requests,
+    /// in which case the update is skipped for this frame.
+    pub fn update(&mut self, elapsed: Duration) {
+        if self.worker.as_ref().map(|worker| !worker.is_busy()).unwrap_or(false) {
+            self.send(MappRequest::Update(elapsed));
+        }
+    }
+
+    pub fn send_event(&mut self, event: Event) {
+        self.send(MappRequest::Event(event));
+    }
+
+    /// Removes the mapp's root entity and all of its descendants from the world, as well as
+    /// any entities the mapp created outside of that subtree.
+    /// The models owned by the container are released along with it, and the `ModelCreate`
+    /// commands still waiting for a model fail.
+    pub fn unload(mut self, world: &mut World) {
+        self.cancel_pending_models();
+
+        let mut entities: HashSet<specs::Entity> = collect_subtree(world, self.root_entity).into_iter().collect();
+
+        entities.extend(self.entity_handles.owned_entities());
+
+        let entities: Vec<specs::Entity> = {
+            let entities_res = world.fetch::<EntitiesRes>();
+            entities.into_iter().filter(|entity| entities_res.is_alive(*entity)).collect()
+        };
+
+        world.delete_entities(&entities[..])
+            .expect("Could not delete the entities of an unloaded mapp.");
+    }
+
+    /// Applies the commands the mapp has sent since the previous call to the world, up to
+    /// `MAX_COMMANDS_PER_FRAME`; the rest are left queued for the next call.
     /// Returns `true`, if the application should be closed, otherwise returns `false`.
-    pub fn process_commands(&mut self, ammolite: &mut Ammolite<MediumData>, world: &mut World, camera: &Rc<RefCell<PitchYawCamera3>>, process_io: bool) -> bool {
+    pub fn process_commands(&mut self, ammolite: &mut Ammolite<MediumData>, model_cache: &mut ModelCache, world: &mut World, camera: &Rc<RefCell<PitchYawCamera3>>, event_distributor: &EventDistributor) -> bool {
+        if self.exit_requested {
+            return true;
+        }
+
         let mut exit = false;
+        let mut command_count = 0;
 
-        while let Some(command) = self.mapp.send_command() {
-            if process_io {
-                self.process_io();
-            }
+        while self.state == MappState::Running && command_count < MAX_COMMANDS_PER_FRAME {
+            let reply = match self.worker.as_mut() {
+                Some(worker) => worker.try_recv(),
+                None => break,
+            };
+            let command = match reply {
+                Ok(MappReply::Command(command)) => command,
+                Ok(MappReply::Idle) => continue,
+                Ok(MappReply::Crashed(crash)) => {
+                    self.crash(crash);
+                    break;
+                },
+                Ok(MappReply::Failed(message)) => {
+                    eprintln!("Mapp {} could not be instantiated: {}", self.name(), message);
+                    self.state = MappState::Crashed;
+                    break;
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.crash(MappCrash {
+                        message: "The worker thread terminated unexpectedly.".to_string(),
+                        frames: Vec::new(),
+                    });
+                    break;
+                },
+            };
 
             // match command.kind {
             //     CommandKind::ModelCreate { .. } => (),
@@ -69,212 +409,802 @@ impl MappContainer {
             // }
 
             let Command { id, kind } = command;
-            let response_kind = match kind {
-                CommandKind::Exit => {
-                    exit = true;
-                    break;
-                },
-                CommandKind::ModelCreate { data } => {
-                    let bytes = data.into_bytes();
-                    let model_index = self.models.len();
-                    println!("Loading model #{}.", model_index);
-                    let start = Instant::now();
-                    let model = Arc::new(ammolite.load_model_slice(&bytes[..]));
-                    self.models.push(model);
-                    println!("Model #{} loaded, took {:.2} seconds.", model_index, start.elapsed().as_secs_f32());
-
-                    Some(CommandResponseKind::ModelCreate {
-                        model: Model(model_index),
-                    })
-                },
-                CommandKind::EntityRootGet => {
-                    Some(CommandResponseKind::EntityRootGet {
-                        // FIXME
-                        root_entity: Entity(self.root_entity.id() as usize),
-                    })
-                },
-                CommandKind::EntityCreate => {
-                    let entity = world.create_entity()
-                        .build();
 
-                    Some(CommandResponseKind::EntityCreate {
-                        entity: Entity(entity.id() as usize),
-                    })
-                },
-                CommandKind::EntityParentSet { entity, parent_entity } => {
-                    let entities = world.fetch::<EntitiesRes>();
-                    // FIXME
-                    let entity = entities.entity(entity.0 as u32);
-                    let parent_entity = parent_entity.map(|parent_entity| entities.entity(parent_entity.0 as u32));
-                    let mut storage = world.write_storage::<ComponentParent>();
-                    let previous_component = if let Some(parent_entity) = parent_entity {
-                        storage.insert(entity, ComponentParent {
-                            entity: parent_entity,
-                        }).expect("An error occurred while inserting a component into storage.")
-                    } else {
-                        storage.remove(entity)
-                    };
-                    let previous_value = previous_component.map(|component| {
-                        Entity(component.entity.id() as usize)
-                    });
+            command_count += 1;
 
-                    Some(CommandResponseKind::EntityParentSet {
-                        previous_parent_entity: previous_value,
-                    })
+            if let CommandKind::Exit = kind {
+                exit = true;
+                break;
+            }
+
+            let command_name = command_name(&kind);
+            let response_kind = match self.execute_command(id, kind, ammolite, model_cache, world, camera, event_distributor) {
+                Ok(Some(response_kind)) => response_kind,
+                // The response is sent once the command completes.
+                Ok(None) => continue,
+                Err(error) => {
+                    if let CommandError::MissingCapability(_) = error {
+                        eprintln!("Mapp {} was denied the command {}: {}.", self.name(), command_name, error);
+                    }
+
+                    CommandResponseKind::Error {
+                        message: error.to_string(),
+                    }
                 },
-                CommandKind::EntityModelSet { entity, model } => {
-                    // dbg!(&entity);
-                    // dbg!(&model);
-                    let entities = world.fetch::<EntitiesRes>();
-                    // FIXME
-                    let entity = entities.entity(entity.0 as u32);
-                    let model = model.map(|model| self.models[model.0].clone());
-
-                    let mut storage = world.write_storage::<ComponentModel>();
-                    let previous_component = if let Some(model) = model {
-                        storage.insert(entity, ComponentModel {
-                            model,
-                        }).expect("An error occurred while inserting a component into storage.")
-                    } else {
-                        storage.remove(entity)
-                    };
-                    let previous_value = previous_component.and_then(|component| {
-                        let mut index_found = None;
-
-                        // FIXME use something better than an O(n) search
-                        for (index, model) in self.models.iter().enumerate() {
-                            if Arc::ptr_eq(model, &component.model) {
-                                index_found = Some(index);
-                                break;
-                            }
-                        }
+            };
 
-                        index_found.map(|index_found| Model(index_found))
+            self.send(MappRequest::CommandResponse(CommandResponse {
+                command_id: id,
+                kind: response_kind,
+            }));
+        }
+
+        self.exit_requested = exit;
+
+        exit
+    }
+
+    pub fn add_model(&mut self, model: LoadedModel) -> Model {
+        self.models.push(Some(model));
+
+        Model(self.models.len() - 1)
+    }
+
+    /// Responds to the `ModelCreate` commands that were waiting for the model to load.
+    pub fn finish_model_load(&mut self, hash: ModelHash, result: &Result<LoadedModel, String>) {
+        let command_ids = match self.pending_models.remove(&hash) {
+            Some(command_ids) => command_ids,
+            None => return,
+        };
+
+        for command_id in command_ids {
+            let response_kind = match result {
+                Ok(model) => {
+                    self.send_event(Event::ModelLoadProgress {
+                        command_id,
+                        progress: 1.0,
                     });
 
-                    Some(CommandResponseKind::EntityModelSet {
-                        previous_model: previous_value,
-                    })
+                    CommandResponseKind::ModelCreate {
+                        model: self.add_model(model.clone()),
+                    }
                 },
-                CommandKind::EntityTransformSet { entity, transform } => {
-                    let entities = world.fetch::<EntitiesRes>();
-                    // FIXME
-                    let entity = entities.entity(entity.0 as u32);
-                    let mut storage = world.write_storage::<ComponentTransformRelative>();
-                    let previous_component = if let Some(transform) = transform {
-                        storage.insert(entity, ComponentTransformRelative {
-                            matrix: transform,
-                        }).expect("An error occurred while inserting a component into storage.")
-                    } else {
-                        storage.remove(entity)
-                    };
-                    let previous_value = previous_component.map(|component| {
-                        component.matrix
+                Err(message) => {
+                    eprintln!("Mapp {} could not load a model: {}", self.name(), message);
+                    self.send_event(Event::ModelLoadFailed {
+                        command_id,
+                        message: message.clone(),
                     });
 
-                    Some(CommandResponseKind::EntityTransformSet {
-                        previous_transform: previous_value,
-                    })
-                },
-                CommandKind::GetViewOrientation {} => {
-                    let views_per_medium = ammolite.views().map(|views|
-                        views.map(|views|
-                            views.iter().map(|view| {
-                                mlib::View {
-                                    pose: {
-                                        (view.pose.orientation.clone().to_homogeneous()
-                                            * Mat4::translation((&view.pose.position).into())
-                                            * camera.borrow().get_view_matrix()).inverse()
-                                    },
-                                    fov: mlib::ViewFov {
-                                        angle_left: view.fov.angle_left,
-                                        angle_right: view.fov.angle_right,
-                                        angle_up: view.fov.angle_up,
-                                        angle_down: view.fov.angle_down,
-                                    },
-                                }
-                            }).collect::<Vec<_>>()
-                        )
-                    ).collect::<Vec<_>>();
-
-                    Some(CommandResponseKind::GetViewOrientation {
-                        views_per_medium,
-                    })
-                },
-                CommandKind::RayTrace { origin, direction } => {
-                    // dbg!(&origin);
-                    // dbg!(&direction);
-                    // unreachable!();
-                    let render_data = world.fetch::<ResourceRenderData>();
-                    let mut world_space_models: Vec<(Entity, WorldSpaceModel)> = Vec::with_capacity(render_data.world_space_models.len());
-
-                    for (id, matrix, model) in &render_data.world_space_models {
-                        world_space_models.push((Entity(*id as usize), WorldSpaceModel {
-                            matrix: matrix.clone(),
-                            model: &model,
-                        }))
+                    CommandResponseKind::Error {
+                        message: format!("could not load the model: {}", message),
                     }
+                },
+            };
+
+            self.send(MappRequest::CommandResponse(CommandResponse {
+                command_id,
+                kind: response_kind,
+            }));
+        }
+    }
+
+    /// Notifies the `ModelCreate` commands waiting for the model of the progress made.
+    pub fn report_model_load_progress(&mut self, hash: ModelHash, progress: f32) {
+        let command_ids = match self.pending_models.get(&hash) {
+            Some(command_ids) => command_ids.clone(),
+            None => return,
+        };
 
-                    let ray = Ray { origin, direction };
-                    let mut closest_intersection: Option<Intersection> = None;
+        for command_id in command_ids {
+            self.send_event(Event::ModelLoadProgress {
+                command_id,
+                progress,
+            });
+        }
+    }
 
-                    for (entity, world_space_model) in &world_space_models {
-                        let ray_intersection = ammolite::raytrace_distance(world_space_model, &ray);
+    /// Fails the `ModelCreate` commands that are still waiting for a model to load, before
+    /// the mapp is unloaded. The responses are sent even if the mapp has requested to exit,
+    /// as its worker still handles the requests sent before it is shut down.
+    fn cancel_pending_models(&mut self) {
+        let worker = match (self.state, self.worker.as_mut()) {
+            (MappState::Running, Some(worker)) => worker,
+            _ => return,
+        };
+        let message = "the mapp was unloaded before the model finished loading".to_string();
 
-                        if let Some(ray_intersection) = ray_intersection {
-                            if closest_intersection.is_none() || (ray_intersection.distance < closest_intersection.as_ref().unwrap().distance_from_origin) {
-                                closest_intersection = Some(Intersection {
-                                    distance_from_origin: ray_intersection.distance,
-                                    position: &ray.origin + (&ray.direction * ray_intersection.distance),
-                                    entity: *entity,
-                                });
-                            }
-                        }
+        for (_, command_ids) in self.pending_models.drain() {
+            for command_id in command_ids {
+                worker.send(MappRequest::Event(Event::ModelLoadFailed {
+                    command_id,
+                    message: message.clone(),
+                }));
+                worker.send(MappRequest::CommandResponse(CommandResponse {
+                    command_id,
+                    kind: CommandResponseKind::Error {
+                        message: format!("could not load the model: {}", message),
+                    },
+                }));
+            }
+        }
+    }
+
+    fn model(&self, model: Model) -> Result<LoadedModel, CommandError> {
+        self.models.get(model.0)
+            .and_then(Option::clone)
+            .ok_or(CommandError::InvalidModel(model))
+    }
+
+    /// Returns handles to the entities in the scope that match the predicate, sorted by handle.
+    /// The own scope is the subtree of the mapp's root entity.
+    /// Entities of other mapps are handed out as handles that may only be referred to.
+    fn query_entities(&mut self, world: &World, scope: QueryScope, predicate: impl Fn(specs::Entity) -> bool) -> Vec<Entity> {
+        let entities = world.fetch::<EntitiesRes>();
+        let mut matching: Vec<specs::Entity> = match scope {
+            QueryScope::Own => collect_subtree(world, self.root_entity).into_iter()
+                .filter(|entity| predicate(*entity))
+                .collect(),
+            QueryScope::Scene => (&*entities).join()
+                .filter(|entity| predicate(*entity))
+                .collect(),
+        };
+
+        // Entities without a handle yet are registered in a deterministic order.
+        matching.sort_by_key(|entity| entity.id());
+
+        let mut handles: Vec<Entity> = matching.into_iter()
+            .map(|entity| self.entity_handles.insert(entity, false))
+            .collect();
+
+        handles.sort_by_key(|handle| handle.0);
+
+        handles
+    }
+
+    /// Returns the hits of the ray on entities that pass the filters of `options`, ordered by
+    /// distance. Unless `options.all_hits` is set, only the closest hit is returned.
+    /// The ray is tested against the triangles of the models, in the space of each model.
+    fn ray_trace(&self, world: &World, ray: &Ray, options: RayTraceOptions) -> Result<Vec<RayHit>, CommandError> {
+        let entities = world.fetch::<EntitiesRes>();
+        let spatial_index = world.fetch::<ResourceSpatialIndex>();
+        let render_data = world.fetch::<ResourceRenderData>();
+        let layers = world.read_storage::<ComponentLayers>();
+        let excluded = options.exclude.iter()
+            .map(|entity| self.entity_handles.resolve(*entity, &entities))
+            .collect::<Result<HashSet<specs::Entity>, CommandError>>()?;
+        let subtree = options.subtree
+            .map(|entity| self.entity_handles.resolve(entity, &entities))
+            .transpose()?;
+        let parents = world.read_storage::<ComponentParent>();
+        let max_distance = options.max_distance.unwrap_or(std::f32::INFINITY);
+        let mut hits: Vec<RayHit> = Vec::new();
+
+        // The spatial index is built during dispatch, so it may refer to entities
+        // deleted since. Rays pass through hidden entities, unless asked to include them.
+        for (bounds_distance, item) in spatial_index.bvh.ray_candidates(ray.origin.0, ray.direction.0) {
+            // The candidates are ordered by the distance to their bounds, which no
+            // intersection with the model can be closer than.
+            if bounds_distance > max_distance {
+                break;
+            }
+
+            if !options.all_hits {
+                if let Some(closest_hit) = hits.first() {
+                    if bounds_distance > closest_hit.distance {
+                        break;
                     }
+                }
+            }
 
-                    Some(CommandResponseKind::RayTrace {
-                        closest_intersection
-                    })
+            let item = &item.value;
+
+            if !entities.is_alive(item.entity) || excluded.contains(&item.entity) {
+                continue;
+            }
+
+            if !options.include_hidden && render_data.hidden.contains(item.entity.id()) {
+                continue;
+            }
+
+            if let Some(root) = subtree {
+                if !is_in_subtree(&parents, item.entity, root) {
+                    continue;
                 }
-            };
+            }
 
-            if let Some(response_kind) = response_kind {
-                self.mapp.receive_command_response(CommandResponse {
-                    command_id: id,
-                    kind: response_kind,
-                });
+            if let Some(layer_mask) = options.layer_mask {
+                let entity_mask = layers.get(item.entity)
+                    .map(|layers| layers.mask)
+                    .unwrap_or(ComponentLayers::DEFAULT);
+
+                if entity_mask & layer_mask == 0 {
+                    continue;
+                }
             }
 
-            if process_io {
-                self.process_io();
+            let affine = Affine::from_matrix(&item.matrix);
+            // A model flattened by its transform has no surface to hit.
+            let inverse = match affine.inverse() {
+                Some(inverse) => inverse,
+                None => continue,
+            };
+            let triangle_hit = item.geometry.intersect_ray(
+                inverse.transform_point(ray.origin.0),
+                inverse.transform_vector(ray.direction.0),
+            );
+            let triangle_hit = match triangle_hit {
+                Some(triangle_hit) if triangle_hit.distance <= max_distance => triangle_hit,
+                _ => continue,
+            };
+            let hit = RayHit {
+                entity: item.entity,
+                distance: triangle_hit.distance,
+                normal: item.geometry.facing_normal(triangle_hit.triangle, &affine, ray.direction.0),
+                texcoord: item.geometry.texcoord(&triangle_hit),
+                source: item.geometry.sources[triangle_hit.triangle],
+            };
+
+            if options.all_hits {
+                hits.push(hit);
+            } else if hits.first().map(|closest_hit| hit.distance < closest_hit.distance).unwrap_or(true) {
+                hits.clear();
+                hits.push(hit);
             }
         }
 
-        if process_io {
-            self.process_io();
-        }
+        hits.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal));
 
-        exit
+        Ok(hits)
     }
 
-    pub fn process_io(&mut self) {
-        let IO { out, err } = self.mapp.flush_io();
+    /// Runs a query against the spatial index and returns the contacts with entities that are
+    /// still alive, visible and not excluded. Shapes are tested against the triangles of the
+    /// models.
+    fn shape_query(
+        &mut self,
+        world: &World,
+        exclude: &[Entity],
+        query: impl FnOnce(&Bvh<SpatialItem>) -> Vec<ShapeContact>,
+    ) -> Result<Vec<ShapeHit>, CommandError> {
+        let entities = world.fetch::<EntitiesRes>();
+        let spatial_index = world.fetch::<ResourceSpatialIndex>();
+        let render_data = world.fetch::<ResourceRenderData>();
+        let excluded = exclude.iter()
+            .map(|entity| self.entity_handles.resolve(*entity, &entities))
+            .collect::<Result<HashSet<specs::Entity>, CommandError>>()?;
+        let contacts: Vec<(specs::Entity, f32, [f32; 3])> = query(&spatial_index.bvh).into_iter()
+            .filter(|contact| entities.is_alive(contact.item.value.entity) && !excluded.contains(&contact.item.value.entity))
+            .filter(|contact| !render_data.hidden.contains(contact.item.value.entity.id()))
+            .map(|contact| (contact.item.value.entity, contact.distance, contact.contact_point))
+            .collect();
 
-        if !out.is_empty() {
-            let stdout = std::io::stdout();
-            let mut handle = stdout.lock();
+        // Entities of other mapps are handed out as handles that may only be referred to.
+        Ok(contacts.into_iter()
+            .map(|(entity, distance, contact_point)| ShapeHit {
+                entity: self.entity_handles.insert(entity, false),
+                distance,
+                contact_point: Vec3(contact_point),
+            })
+            .collect())
+    }
 
-            handle.write_all(&out[..])
-                .expect("Could not redirect the module's stdout to the host stdout.");
+    /// Executes the command, returning `None`, if its response is deferred.
+    fn execute_command(
+        &mut self,
+        id: usize,
+        kind: CommandKind,
+        ammolite: &mut Ammolite<MediumData>,
+        model_cache: &mut ModelCache,
+        world: &mut World,
+        camera: &Rc<RefCell<PitchYawCamera3>>,
+        event_distributor: &EventDistributor,
+    ) -> Result<Option<CommandResponseKind>, CommandError> {
+        if let Some(capability) = Capability::required_by(&kind) {
+            if !self.capabilities.contains(&capability) {
+                return Err(CommandError::MissingCapability(capability));
+            }
         }
 
-        if !err.is_empty() {
-            let stderr = std::io::stderr();
-            let mut handle = stderr.lock();
+        Ok(Some(match kind {
+            CommandKind::Exit => unreachable!("The exit command is handled by `process_commands`."),
+            CommandKind::ModelCreate { data } => {
+                match model_cache.request(data.into_bytes()) {
+                    ModelRequest::Loaded(model) => {
+                        CommandResponseKind::ModelCreate {
+                            model: self.add_model(model),
+                        }
+                    },
+                    ModelRequest::Pending(hash) => {
+                        println!("Mapp {} is waiting for a model to load.", self.name());
+                        self.pending_models.entry(hash).or_default().push(id);
+                        self.send_event(Event::ModelLoadProgress {
+                            command_id: id,
+                            progress: 0.0,
+                        });
+
+                        return Ok(None);
+                    },
+                }
+            },
+            CommandKind::ModelDelete { model } => {
+                let model_arc = self.models.get_mut(model.0)
+                    .and_then(Option::take)
+                    .ok_or(CommandError::InvalidModel(model))?;
+                // The GPU resources are freed along with the last `Arc`, once no
+                // `ComponentModel` refers to the model anymore.
+                let in_use = world.read_storage::<ComponentModel>().join()
+                    .any(|component| Arc::ptr_eq(&component.model, &model_arc.model));
 
-            handle.write_all(&err[..])
-                .expect("Could not redirect the module's stderr to the host stderr.");
-        }
+                CommandResponseKind::ModelDelete {
+                    in_use,
+                }
+            },
+            CommandKind::EntityRootGet => {
+                CommandResponseKind::EntityRootGet {
+                    root_entity: self.entity_handles.insert(self.root_entity, true),
+                }
+            },
+            CommandKind::EntityCreate => {
+                let entity = world.create_entity()
+                    .build();
+
+                CommandResponseKind::EntityCreate {
+                    entity: self.entity_handles.insert(entity, true),
+                }
+            },
+            CommandKind::EntityDelete { entity, recursive } => {
+                let entity = self.entity_handles.resolve_owned(entity, &world.fetch::<EntitiesRes>())?;
+
+                if entity == self.root_entity {
+                    return Err(CommandError::RootEntityDeletion);
+                }
+
+                for deleted_entity in delete_entity(world, entity, recursive) {
+                    self.entity_handles.remove(deleted_entity);
+                }
+
+                CommandResponseKind::EntityDelete
+            },
+            CommandKind::EntityParentSet { entity, parent_entity } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let parent_entity = parent_entity
+                    .map(|parent_entity| self.entity_handles.resolve_owned(parent_entity, &entities))
+                    .transpose()?;
+
+                validate_parent(&world.read_storage::<ComponentParent>(), entity, parent_entity, self.root_entity)?;
+
+                let mut storage = world.write_storage::<ComponentParent>();
+                let previous_component = if let Some(parent_entity) = parent_entity {
+                    storage.insert(entity, ComponentParent {
+                        entity: parent_entity,
+                    }).expect("An error occurred while inserting a component into storage.")
+                } else {
+                    storage.remove(entity)
+                };
+                // Parents the mapp has no handle to, such as the scene root, are not revealed.
+                let previous_value = previous_component.and_then(|component| {
+                    self.entity_handles.get(component.entity)
+                });
+
+                CommandResponseKind::EntityParentSet {
+                    previous_parent_entity: previous_value,
+                }
+            },
+            CommandKind::EntityModelSet { entity, model } => {
+                // dbg!(&entity);
+                // dbg!(&model);
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let model = model
+                    .map(|model| self.model(model))
+                    .transpose()?;
+
+                let mut storage = world.write_storage::<ComponentModel>();
+                let previous_component = if let Some(model) = model {
+                    storage.insert(entity, ComponentModel {
+                        model: model.model,
+                        geometry: model.geometry,
+                    }).expect("An error occurred while inserting a component into storage.")
+                } else {
+                    storage.remove(entity)
+                };
+                let previous_value = previous_component.and_then(|component| {
+                    let mut index_found = None;
+
+                    // FIXME use something better than an O(n) search
+                    for (index, model) in self.models.iter().enumerate() {
+                        if model.as_ref().map(|model| Arc::ptr_eq(&model.model, &component.model)).unwrap_or(false) {
+                            index_found = Some(index);
+                            break;
+                        }
+                    }
+
+                    index_found.map(|index_found| Model(index_found))
+                });
+
+                CommandResponseKind::EntityModelSet {
+                    previous_model: previous_value,
+                }
+            },
+            CommandKind::EntityTransformSet { entity, transform } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let mut storage = world.write_storage::<ComponentTransformRelative>();
+                let previous_component = if let Some(transform) = transform {
+                    storage.insert(entity, ComponentTransformRelative {
+                        matrix: transform,
+                    }).expect("An error occurred while inserting a component into storage.")
+                } else {
+                    storage.remove(entity)
+                };
+                let previous_value = previous_component.map(|component| {
+                    component.matrix
+                });
+
+                // The matrix replaces the decomposed transform, if any, and with it the
+                // animation driving that transform.
+                world.write_storage::<ComponentTransformDecomposed>().remove(entity);
+                world.write_storage::<ComponentAnimation>().remove(entity);
+
+                CommandResponseKind::EntityTransformSet {
+                    previous_transform: previous_value,
+                }
+            },
+            CommandKind::EntityTransformDecomposedSet { entity, transform } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let mut storage = world.write_storage::<ComponentTransformDecomposed>();
+                let previous_component = if let Some(transform) = transform {
+                    // `SystemTransformCompose` builds the matrix during the next dispatch.
+                    storage.insert(entity, ComponentTransformDecomposed {
+                        translation: transform.translation,
+                        rotation: transform.rotation,
+                        scale: transform.scale,
+                    }).expect("An error occurred while inserting a component into storage.")
+                } else {
+                    world.write_storage::<ComponentTransformRelative>().remove(entity);
+                    world.write_storage::<ComponentAnimation>().remove(entity);
+                    storage.remove(entity)
+                };
+                let previous_value = previous_component.map(decomposed_transform);
+
+                CommandResponseKind::EntityTransformDecomposedSet {
+                    previous_transform: previous_value,
+                }
+            },
+            CommandKind::EntityTransformDecomposedGet { entity } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                // Entities that were never given a decomposed transform are decomposed from
+                // their relative transform.
+                let transform = world.read_storage::<ComponentTransformDecomposed>().get(entity)
+                    .cloned()
+                    .or_else(|| {
+                        world.read_storage::<ComponentTransformRelative>().get(entity)
+                            .map(|transform| ComponentTransformDecomposed::from_matrix(&transform.matrix))
+                    })
+                    .map(decomposed_transform);
+
+                CommandResponseKind::EntityTransformDecomposedGet {
+                    transform,
+                }
+            },
+            CommandKind::EntityVisibleSet { entity, visible } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let previous_component = world.write_storage::<ComponentVisible>()
+                    .insert(entity, ComponentVisible { visible })
+                    .expect("An error occurred while inserting a component into storage.");
+                let previous_value = previous_component
+                    .map(|component| component.visible)
+                    .unwrap_or(true);
+
+                CommandResponseKind::EntityVisibleSet {
+                    previous_visible: previous_value,
+                }
+            },
+            CommandKind::EntityNameSet { entity, name } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let mut storage = world.write_storage::<ComponentName>();
+                let previous_component = if let Some(name) = name {
+                    storage.insert(entity, ComponentName { name })
+                        .expect("An error occurred while inserting a component into storage.")
+                } else {
+                    storage.remove(entity)
+                };
+
+                CommandResponseKind::EntityNameSet {
+                    previous_name: previous_component.map(|component| component.name),
+                }
+            },
+            CommandKind::EntityTagsSet { entity, tags } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let mut storage = world.write_storage::<ComponentTags>();
+                let previous_component = if tags.is_empty() {
+                    storage.remove(entity)
+                } else {
+                    storage.insert(entity, ComponentTags { tags: tags.into_iter().collect() })
+                        .expect("An error occurred while inserting a component into storage.")
+                };
+
+                CommandResponseKind::EntityTagsSet {
+                    previous_tags: previous_component
+                        .map(|component| component.tags.into_iter().collect())
+                        .unwrap_or_default(),
+                }
+            },
+            CommandKind::EntityFindByName { name, scope } => {
+                let names = world.read_storage::<ComponentName>();
+                let entities = self.query_entities(world, scope, |entity| {
+                    names.get(entity).map(|component| component.name == name).unwrap_or(false)
+                });
+
+                CommandResponseKind::EntityFindByName {
+                    entities,
+                }
+            },
+            CommandKind::EntityListByTag { tag, scope } => {
+                let tags = world.read_storage::<ComponentTags>();
+                let entities = self.query_entities(world, scope, |entity| {
+                    tags.get(entity).map(|component| component.tags.contains(&tag)).unwrap_or(false)
+                });
+
+                CommandResponseKind::EntityListByTag {
+                    entities,
+                }
+            },
+            CommandKind::EntityAnimationSet { entity: handle, animation } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(handle, &entities)?;
+                let mut storage = world.write_storage::<ComponentAnimation>();
+
+                if let Some(animation) = animation {
+                    if !world.read_storage::<ComponentTransformDecomposed>().contains(entity) {
+                        return Err(CommandError::MissingDecomposedTransform(handle));
+                    }
+
+                    let clip = animation_clip(animation.translation, animation.rotation, animation.scale);
+
+                    if !clip.is_valid() {
+                        return Err(CommandError::InvalidAnimation);
+                    }
+
+                    storage.insert(entity, ComponentAnimation {
+                        clip,
+                        start: world.fetch::<ResourceTimeElapsed>().0,
+                        looping: animation.looping,
+                    }).expect("An error occurred while inserting a component into storage.");
+                } else {
+                    storage.remove(entity);
+                }
+
+                CommandResponseKind::EntityAnimationSet
+            },
+            CommandKind::EntityLightSet { entity, light } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let mut storage = world.write_storage::<ComponentLight>();
+                let previous_component = if let Some(light) = light {
+                    let light = light_component(light);
+
+                    if !light.is_valid() {
+                        return Err(CommandError::InvalidLight);
+                    }
+
+                    storage.insert(entity, light)
+                        .expect("An error occurred while inserting a component into storage.")
+                } else {
+                    storage.remove(entity)
+                };
+                let previous_value = previous_component.map(light_from_component);
+
+                CommandResponseKind::EntityLightSet {
+                    previous_light: previous_value,
+                }
+            },
+            CommandKind::GetViewOrientation {} => {
+                let views_per_medium = ammolite.views().map(|views|
+                    views.map(|views|
+                        views.iter().map(|view| {
+                            mlib::View {
+                                pose: {
+                                    (view.pose.orientation.clone().to_homogeneous()
+                                        * Mat4::translation((&view.pose.position).into())
+                                        * camera.borrow().get_view_matrix()).inverse()
+                                },
+                                fov: mlib::ViewFov {
+                                    angle_left: view.fov.angle_left,
+                                    angle_right: view.fov.angle_right,
+                                    angle_up: view.fov.angle_up,
+                                    angle_down: view.fov.angle_down,
+                                },
+                            }
+                        }).collect::<Vec<_>>()
+                    )
+                ).collect::<Vec<_>>();
+
+                CommandResponseKind::GetViewOrientation {
+                    views_per_medium,
+                }
+            },
+            CommandKind::RayTrace { origin, direction, options } => {
+                // dbg!(&origin);
+                // dbg!(&direction);
+                // unreachable!();
+                let ray = Ray { origin, direction };
+                let all_hits = options.all_hits;
+                let hits = self.ray_trace(world, &ray, options)?;
+
+                // Entities of other mapps are handed out as handles that may only be referred to.
+                let intersections: Vec<Intersection> = hits.into_iter()
+                    .map(|hit| Intersection {
+                        distance_from_origin: hit.distance,
+                        position: &ray.origin + (&ray.direction * hit.distance),
+                        entity: self.entity_handles.insert(hit.entity, false),
+                        normal: Vec3(hit.normal),
+                        texcoord: hit.texcoord.map(Vec2),
+                        mesh_index: hit.source.mesh_index,
+                        primitive_index: hit.source.primitive_index,
+                        triangle_index: hit.source.triangle_index,
+                    })
+                    .collect();
+
+                if all_hits {
+                    CommandResponseKind::RayTraceAll {
+                        intersections,
+                    }
+                } else {
+                    CommandResponseKind::RayTrace {
+                        closest_intersection: intersections.into_iter().next(),
+                    }
+                }
+            },
+            CommandKind::ShapeCast { shape, direction, max_distance, exclude } => {
+                let volume = volume(shape)?;
+
+                if !direction.0.iter().all(|component| component.is_finite()) || !max_distance.is_finite() || max_distance < 0.0 {
+                    return Err(CommandError::InvalidCast);
+                }
+
+                let hits = self.shape_query(world, &exclude, |bvh| {
+                    shape::cast(bvh, &volume, direction.0, max_distance)
+                })?;
+
+                CommandResponseKind::ShapeCast {
+                    hits,
+                }
+            },
+            CommandKind::ShapeOverlap { shape, exclude } => {
+                let volume = volume(shape)?;
+                let hits = self.shape_query(world, &exclude, |bvh| {
+                    shape::overlap(bvh, &volume)
+                })?;
+
+                CommandResponseKind::ShapeOverlap {
+                    hits,
+                }
+            },
+            CommandKind::EntityLayersSet { entity, mask } => {
+                let entities = world.fetch::<EntitiesRes>();
+                let entity = self.entity_handles.resolve_owned(entity, &entities)?;
+                let previous_component = world.write_storage::<ComponentLayers>()
+                    .insert(entity, ComponentLayers { mask })
+                    .expect("An error occurred while inserting a component into storage.");
+
+                CommandResponseKind::EntityLayersSet {
+                    previous_mask: previous_component
+                        .map(|component| component.mask)
+                        .unwrap_or(ComponentLayers::DEFAULT),
+                }
+            },
+            CommandKind::MessageSend { recipient, data } => {
+                let recipient_handle = event_distributor.resolve_recipient(&recipient)
+                    .ok_or(CommandError::UnknownRecipient(recipient))?;
+
+                event_distributor.send_to(recipient_handle, Event::Message {
+                    sender: self.handle,
+                    sender_name: self.name().to_string(),
+                    data,
+                });
+
+                CommandResponseKind::MessageSend {
+                    recipient: recipient_handle,
+                }
+            },
+        }))
+    }
+}
+
+fn decomposed_transform(component: ComponentTransformDecomposed) -> TransformDecomposed {
+    TransformDecomposed {
+        translation: component.translation,
+        rotation: component.rotation,
+        scale: component.scale,
+    }
+}
+
+fn animation_clip(
+    translation: Vec<mlib::Keyframe<Vec3>>,
+    rotation: Vec<mlib::Keyframe<Quaternion>>,
+    scale: Vec<mlib::Keyframe<Vec3>>,
+) -> AnimationClip {
+    fn track<T>(keyframes: Vec<mlib::Keyframe<T>>) -> Vec<Keyframe<T>> {
+        keyframes.into_iter()
+            .map(|keyframe| Keyframe {
+                time: keyframe.time,
+                value: keyframe.value,
+                easing: match keyframe.easing {
+                    mlib::Easing::Step => Easing::Step,
+                    mlib::Easing::Linear => Easing::Linear,
+                    mlib::Easing::EaseIn => Easing::EaseIn,
+                    mlib::Easing::EaseOut => Easing::EaseOut,
+                    mlib::Easing::EaseInOut => Easing::EaseInOut,
+                },
+            })
+            .collect()
+    }
+
+    AnimationClip {
+        translation: track(translation),
+        rotation: track(rotation),
+        scale: track(scale),
+    }
+}
+
+fn volume(shape: Shape) -> Result<Volume, CommandError> {
+    let volume = match shape {
+        Shape::Sphere { center, radius } => Volume::Sphere {
+            center: center.0,
+            radius,
+        },
+        Shape::Box { center, half_extents, rotation } => Volume::Box {
+            center: center.0,
+            half_extents: half_extents.0,
+            rotation: rotation.0,
+        },
+        Shape::Capsule { start, end, radius } => Volume::Capsule {
+            start: start.0,
+            end: end.0,
+            radius,
+        },
+    };
+
+    if volume.is_valid() {
+        Ok(volume)
+    } else {
+        Err(CommandError::InvalidShape)
+    }
+}
+
+fn light_component(light: mlib::Light) -> ComponentLight {
+    ComponentLight {
+        kind: match light.kind {
+            mlib::LightKind::Directional => crate::ecs::LightKind::Directional,
+            mlib::LightKind::Point => crate::ecs::LightKind::Point,
+            mlib::LightKind::Spot { inner_cone_angle, outer_cone_angle } =>
+                crate::ecs::LightKind::Spot { inner_cone_angle, outer_cone_angle },
+        },
+        color: light.color,
+        intensity: light.intensity,
+        range: light.range,
+    }
+}
+
+fn light_from_component(component: ComponentLight) -> mlib::Light {
+    mlib::Light {
+        kind: match component.kind {
+            crate::ecs::LightKind::Directional => mlib::LightKind::Directional,
+            crate::ecs::LightKind::Point => mlib::LightKind::Point,
+            crate::ecs::LightKind::Spot { inner_cone_angle, outer_cone_angle } =>
+                mlib::LightKind::Spot { inner_cone_angle, outer_cone_angle },
+        },
+        color: component.color,
+        intensity: component.intensity,
+        range: component.range,
     }
 }
 
@@ -287,3 +1217,169 @@ pub fn example() {
     // println!("{:?}", mapp.test("3".to_string()));
     // println!("{:#?}", mapp.get_model_matrices(3.14));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(bytes: &mut Vec<u8>, mut value: u32) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+
+            value >>= 7;
+
+            if value == 0 {
+                bytes.push(byte);
+                return;
+            }
+
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn push_section(bytes: &mut Vec<u8>, code: u8, content: &[u8]) {
+        bytes.push(code);
+        push_u32(bytes, content.len() as u32);
+        bytes.extend_from_slice(content);
+    }
+
+    /// Encodes a module exporting a function `() -> ()` under each of `functions` and
+    /// a single memory under each of `memories`.
+    fn module(functions: &[&str], memories: &[&str]) -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        let mut function_section = Vec::new();
+        let mut memory_section = Vec::new();
+        let mut export_section = Vec::new();
+        let mut code_section = Vec::new();
+
+        push_u32(&mut function_section, functions.len() as u32);
+        push_u32(&mut code_section, functions.len() as u32);
+
+        for _ in functions {
+            // The type index, and a body without locals
+            function_section.push(0x00);
+            code_section.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        }
+
+        memory_section.extend_from_slice(&[0x01, 0x00, 0x00]);
+        push_u32(&mut export_section, (functions.len() + memories.len()) as u32);
+
+        for (kind, names) in &[(0x00, functions), (0x02, memories)] {
+            for (index, name) in names.iter().enumerate() {
+                push_u32(&mut export_section, name.len() as u32);
+                export_section.extend_from_slice(name.as_bytes());
+                export_section.push(*kind);
+                push_u32(&mut export_section, if *kind == 0x00 { index as u32 } else { 0 });
+            }
+        }
+
+        push_section(&mut bytes, 1, &[0x01, 0x60, 0x00, 0x00]);
+        push_section(&mut bytes, 3, &function_section);
+        push_section(&mut bytes, 5, &memory_section);
+        push_section(&mut bytes, 7, &export_section);
+        push_section(&mut bytes, 10, &code_section);
+
+        bytes
+    }
+
+    fn write_module(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("metaview-module-{}-{}.wasm", std::process::id(), name));
+
+        std::fs::write(&path, bytes).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn modules_exporting_the_required_functions_are_valid() {
+        let path = write_module("valid", &module(REQUIRED_EXPORTS, &["memory"]));
+
+        validate_module(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_exports_are_reported() {
+        let path = write_module("missing-export", &module(&REQUIRED_EXPORTS[..REQUIRED_EXPORTS.len() - 1], &[]));
+
+        match validate_module(&path) {
+            Err(MappLoadError::MissingExport { export, .. }) => assert_eq!(export, REQUIRED_EXPORTS[REQUIRED_EXPORTS.len() - 1]),
+            result => panic!("unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn exports_of_the_wrong_kind_are_abi_mismatches() {
+        let path = write_module("abi-mismatch", &module(&REQUIRED_EXPORTS[1..], &[REQUIRED_EXPORTS[0]]));
+
+        match validate_module(&path) {
+            Err(MappLoadError::AbiMismatch { export, .. }) => assert_eq!(export, REQUIRED_EXPORTS[0]),
+            result => panic!("unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn malformed_modules_are_invalid_wasm() {
+        let path = write_module("invalid-wasm", b"\0asm\x01\0\0\0\x07\x05\x01");
+
+        match validate_module(&path) {
+            Err(MappLoadError::InvalidWasm { .. }) => (),
+            result => panic!("unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn missing_modules_are_not_found() {
+        let path = std::env::temp_dir().join("metaview-module-missing.wasm");
+
+        match validate_module(&path) {
+            Err(MappLoadError::FileNotFound { .. }) => (),
+            result => panic!("unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn reparenting_rejects_cycles_and_the_root_entity() {
+        let mut world = World::new();
+
+        world.register::<ComponentParent>();
+
+        let root = world.create_entity().build();
+        let child = world.create_entity().with(ComponentParent { entity: root }).build();
+        let grandchild = world.create_entity().with(ComponentParent { entity: child }).build();
+        let other = world.create_entity().build();
+        let parents = world.read_storage::<ComponentParent>();
+
+        assert!(validate_parent(&parents, grandchild, Some(root), root).is_ok());
+        assert!(validate_parent(&parents, grandchild, None, root).is_ok());
+        assert!(validate_parent(&parents, child, Some(other), root).is_ok());
+
+        match validate_parent(&parents, child, Some(child), root) {
+            Err(CommandError::ParentCycle) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        match validate_parent(&parents, child, Some(grandchild), root) {
+            Err(CommandError::ParentCycle) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        for parent_entity in &[Some(other), None] {
+            match validate_parent(&parents, root, *parent_entity, root) {
+                Err(CommandError::RootEntityReparenting) => (),
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    /// Checks `REQUIRED_EXPORTS` against the exports mlib actually generates and binds to.
+    /// The example mapp has to be built with `wasm-pack` beforehand.
+    #[cfg(feature = "native-example-mapp")]
+    #[test]
+    fn example_mapp_satisfies_the_required_exports() {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../example-mapp/pkg/example_mapp.wasm"));
+
+        validate_module(path).expect("The example mapp does not export the required functions.");
+        MappExports::load_file(path).expect("The host could not bind to the example mapp.");
+    }
+}
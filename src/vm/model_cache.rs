@@ -0,0 +1,514 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+use std::time::Instant;
+use sha2::{Sha256, Digest};
+use gltf::{buffer, Gltf, Node};
+use gltf::mesh::Mode;
+use ammolite::Ammolite;
+use ammolite::model::Model;
+use crate::ecs::bvh::{ModelGeometry, TriangleSource};
+use crate::medium::MediumData;
+use crate::vm::worker::MappCrash;
+
+pub type ModelHash = [u8; 32];
+
+pub fn hash_to_hex(hash: &ModelHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A model uploaded to the renderer, along with its geometry, which spatial queries are
+/// tested against.
+#[derive(Clone)]
+pub struct LoadedModel {
+    pub model: Arc<Model>,
+    pub geometry: Arc<ModelGeometry>,
+}
+
+pub enum ModelRequest {
+    Loaded(LoadedModel),
+    /// The model is being loaded in the background, see `ModelCache::poll`.
+    Pending(ModelHash),
+}
+
+/// The state of a model being loaded, as reported by `ModelCache::poll`.
+pub enum ModelLoadUpdate {
+    /// The fraction of the model that has been loaded so far.
+    Progress(ModelHash, f32),
+    /// The model has been loaded, or the reason it could not be.
+    Finished(ModelHash, Result<LoadedModel, String>),
+}
+
+/// Messages sent from the loader thread.
+enum LoaderMessage {
+    Progress(ModelHash, f32),
+    Decoded(ModelHash, Result<ModelGeometry, String>),
+}
+
+struct CacheEntry {
+    model: Weak<Model>,
+    geometry: Weak<ModelGeometry>,
+    /// The file holding the bytes the model was loaded from, if any, so that it can be saved
+    /// along with a scene.
+    path: Option<PathBuf>,
+}
+
+impl CacheEntry {
+    fn upgrade(&self) -> Option<LoadedModel> {
+        Some(LoadedModel {
+            model: self.model.upgrade()?,
+            geometry: self.geometry.upgrade()?,
+        })
+    }
+}
+
+/// Shares models loaded from identical bytes, across all mapps.
+///
+/// Models are hashed and decoded on a background thread, so that large models do not stall
+/// rendering. Uploading a decoded model requires the renderer, so it happens on the render
+/// thread, in `poll`, one model per call. Only weak references are kept, so a model is still
+/// released as soon as no mapp or `ComponentModel` refers to it.
+pub struct ModelCache {
+    models: HashMap<ModelHash, CacheEntry>,
+    pending: HashMap<ModelHash, Arc<Vec<u8>>>,
+    /// Models that have been decoded and are waiting to be uploaded.
+    decoded: VecDeque<(ModelHash, ModelGeometry)>,
+    /// Updates received during `load_blocking`, yet to be reported by `poll`.
+    ready: Vec<ModelLoadUpdate>,
+    job_sender: Sender<(ModelHash, Arc<Vec<u8>>)>,
+    message_receiver: Receiver<LoaderMessage>,
+    /// The directory the bytes of uploaded models are written to, see `set_source_directory`.
+    source_directory: Option<PathBuf>,
+    hits: usize,
+    misses: usize,
+}
+
+impl ModelCache {
+    pub fn new() -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<(ModelHash, Arc<Vec<u8>>)>();
+        let (message_sender, message_receiver) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("model loader".to_string())
+            .spawn(move || {
+                for (hash, bytes) in job_receiver {
+                    let progress_sender = message_sender.clone();
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        decode_model(&bytes[..], |progress| {
+                            progress_sender.send(LoaderMessage::Progress(hash, progress)).ok();
+                        })
+                    }));
+                    let result = result
+                        .map_err(|payload| MappCrash::from_panic(payload).message)
+                        .and_then(|result| result);
+
+                    if message_sender.send(LoaderMessage::Decoded(hash, result)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Could not spawn the model loader thread.");
+
+        Self {
+            models: HashMap::new(),
+            pending: HashMap::new(),
+            decoded: VecDeque::new(),
+            ready: Vec::new(),
+            job_sender,
+            message_receiver,
+            source_directory: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The number of requests served by a model that was loaded or being loaded already.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// The number of requests that started loading a model.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    pub fn hash(bytes: &[u8]) -> ModelHash {
+        let mut hash = [0; 32];
+
+        hash.copy_from_slice(&Sha256::digest(bytes)[..]);
+
+        hash
+    }
+
+    /// Returns the model previously loaded from the same bytes, if it is still alive,
+    /// otherwise starts loading the model, unless it is being loaded already.
+    pub fn request(&mut self, bytes: Vec<u8>) -> ModelRequest {
+        let hash = Self::hash(&bytes[..]);
+
+        if let Some(loaded) = self.models.get(&hash).and_then(CacheEntry::upgrade) {
+            self.hits += 1;
+
+            return ModelRequest::Loaded(loaded);
+        }
+
+        if self.pending.contains_key(&hash) {
+            self.hits += 1;
+
+            return ModelRequest::Pending(hash);
+        }
+
+        self.misses += 1;
+
+        let bytes = Arc::new(bytes);
+
+        self.pending.insert(hash, bytes.clone());
+        self.job_sender.send((hash, bytes))
+            .expect("The model loader thread terminated unexpectedly.");
+
+        ModelRequest::Pending(hash)
+    }
+
+    /// Like `request`, but waits for the model to be loaded.
+    /// The updates of other models received in the meantime are still reported by `poll`.
+    pub fn load_blocking(&mut self, ammolite: &mut Ammolite<MediumData>, bytes: Vec<u8>) -> Result<LoadedModel, String> {
+        let hash = match self.request(bytes) {
+            ModelRequest::Loaded(loaded) => return Ok(loaded),
+            ModelRequest::Pending(hash) => hash,
+        };
+
+        // The model might have been decoded already, for a mapp that is still waiting for it.
+        if let Some(position) = self.decoded.iter().position(|(decoded, _)| *decoded == hash) {
+            let (_, geometry) = self.decoded.remove(position).unwrap();
+
+            return self.finish_blocking(ammolite, hash, Ok(geometry));
+        }
+
+        loop {
+            let message = self.message_receiver.recv()
+                .expect("The model loader thread terminated unexpectedly.");
+
+            match message {
+                LoaderMessage::Decoded(decoded_hash, result) if decoded_hash == hash => {
+                    return self.finish_blocking(ammolite, hash, result);
+                },
+                message => {
+                    if let Some(update) = self.receive(message) {
+                        self.ready.push(update);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Like `load_blocking`, but reads the bytes from a file, which is remembered as the
+    /// source of the model, unless it is stored in the source directory already.
+    pub fn load_file_blocking(&mut self, ammolite: &mut Ammolite<MediumData>, path: &Path) -> Result<LoadedModel, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        let hash = Self::hash(&bytes[..]);
+        let model = self.load_blocking(ammolite, bytes)?;
+
+        if let Some(entry) = self.models.get_mut(&hash) {
+            entry.path.get_or_insert_with(|| path.to_path_buf());
+        }
+
+        Ok(model)
+    }
+
+    /// Uploads the model loaded by `load_blocking`, reporting it to the mapps that may be
+    /// waiting for it as well.
+    fn finish_blocking(&mut self, ammolite: &mut Ammolite<MediumData>, hash: ModelHash, result: Result<ModelGeometry, String>) -> Result<LoadedModel, String> {
+        let result = match result {
+            Ok(geometry) => self.upload(ammolite, hash, geometry),
+            Err(message) => self.fail(hash, message),
+        };
+
+        self.ready.push(ModelLoadUpdate::Finished(hash, result.clone()));
+
+        result
+    }
+
+    /// Writes the bytes of the models uploaded from now on into `directory`, in files named
+    /// after their hash, so that they need not be kept in memory to be saved with a scene.
+    pub fn set_source_directory(&mut self, directory: PathBuf) -> std::io::Result<()> {
+        std::fs::create_dir_all(&directory)?;
+
+        self.source_directory = Some(directory);
+
+        Ok(())
+    }
+
+    /// Returns the hash of the model and the file its bytes are stored in, if it was loaded
+    /// through the cache from a file or while a source directory was set.
+    pub fn source(&self, model: &Arc<Model>) -> Option<(ModelHash, PathBuf)> {
+        self.models.iter()
+            .find(|(_, entry)| entry.model.upgrade().map(|cached| Arc::ptr_eq(&cached, model)).unwrap_or(false))
+            .and_then(|(hash, entry)| entry.path.clone().map(|path| (*hash, path)))
+    }
+
+    /// Writes the bytes to the source directory, if one is set, returning the path written to.
+    fn store(&self, hash: &ModelHash, bytes: &[u8]) -> Option<PathBuf> {
+        let path = self.source_directory.as_ref()?.join(hash_to_hex(hash));
+
+        if !path.exists() {
+            if let Err(error) = std::fs::write(&path, bytes) {
+                eprintln!("Could not store the model in {}: {}", path.display(), error);
+                return None;
+            }
+        }
+
+        Some(path)
+    }
+
+    /// Handles a message of the loader thread, returning the update to report, if any.
+    fn receive(&mut self, message: LoaderMessage) -> Option<ModelLoadUpdate> {
+        match message {
+            LoaderMessage::Progress(hash, progress) => Some(ModelLoadUpdate::Progress(hash, progress)),
+            LoaderMessage::Decoded(hash, Ok(geometry)) => {
+                self.decoded.push_back((hash, geometry));
+                None
+            },
+            LoaderMessage::Decoded(hash, Err(message)) => {
+                Some(ModelLoadUpdate::Finished(hash, self.fail(hash, message)))
+            },
+        }
+    }
+
+    fn fail(&mut self, hash: ModelHash, message: String) -> Result<LoadedModel, String> {
+        self.pending.remove(&hash)
+            .expect("Loaded a model that was not requested.");
+
+        Err(message)
+    }
+
+    /// Creates the renderer's resources of a decoded model.
+    ///
+    /// TODO: `Ammolite` only loads models from their bytes, so the document is parsed once
+    /// more here, on the render thread, after `decode_model` has parsed it on the loader
+    /// thread. Hand the decoded buffers over instead, once the renderer accepts them.
+    fn upload(&mut self, ammolite: &mut Ammolite<MediumData>, hash: ModelHash, geometry: ModelGeometry) -> Result<LoadedModel, String> {
+        let bytes = self.pending.remove(&hash)
+            .expect("Loaded a model that was not requested.");
+        let start = Instant::now();
+        let model = panic::catch_unwind(AssertUnwindSafe(|| ammolite.load_model_slice(&bytes[..])))
+            .map_err(|payload| MappCrash::from_panic(payload).message)?;
+        let model = Arc::new(model);
+        let geometry = Arc::new(geometry);
+
+        println!(
+            "Model uploaded, took {:.2} seconds; {} model requests were served from the cache, {} were not.",
+            start.elapsed().as_secs_f32(), self.hits, self.misses,
+        );
+
+        let path = self.store(&hash, &bytes[..]);
+
+        self.models.insert(hash, CacheEntry {
+            model: Arc::downgrade(&model),
+            geometry: Arc::downgrade(&geometry),
+            path,
+        });
+
+        Ok(LoadedModel { model, geometry })
+    }
+
+    /// Collects the progress of the models being loaded since the previous call, and uploads
+    /// at most one decoded model, so that uploads are spread across frames.
+    pub fn poll(&mut self, ammolite: &mut Ammolite<MediumData>) -> Vec<ModelLoadUpdate> {
+        let mut updates = std::mem::replace(&mut self.ready, Vec::new());
+
+        while let Ok(message) = self.message_receiver.try_recv() {
+            updates.extend(self.receive(message));
+        }
+
+        if let Some((hash, geometry)) = self.decoded.pop_front() {
+            let result = self.upload(ammolite, hash, geometry);
+
+            updates.push(ModelLoadUpdate::Finished(hash, result));
+
+            // Drop the entries of released models, so that the map does not grow indefinitely.
+            self.models.retain(|_, entry| entry.model.strong_count() > 0);
+        }
+
+        updates
+    }
+}
+
+/// Decodes the glTF document and reads the geometry of each of its meshes, so that malformed
+/// models are rejected before they reach the renderer, and so that spatial queries can be
+/// tested against the triangles. Reports the fraction of the work done after the buffers and
+/// after each mesh; the upload is the last step.
+fn decode_model(bytes: &[u8], mut progress: impl FnMut(f32)) -> Result<ModelGeometry, String> {
+    let Gltf { document, blob } = Gltf::from_slice(bytes)
+        .map_err(|error| format!("invalid glTF: {}", error))?;
+    let buffers = document.buffers()
+        .map(|buffer| {
+            let data = match buffer.source() {
+                buffer::Source::Bin => blob.as_ref()
+                    .map(|blob| Cow::Borrowed(&blob[..]))
+                    .ok_or_else(|| "the binary chunk is missing".to_string())?,
+                buffer::Source::Uri(uri) => Cow::Owned(decode_data_uri(uri)?),
+            };
+
+            if data.len() < buffer.length() {
+                return Err(format!("buffer {} is shorter than declared", buffer.index()));
+            }
+
+            Ok(data)
+        })
+        .collect::<Result<Vec<Cow<[u8]>>, String>>()?;
+    let steps = (document.meshes().count() + 2) as f32;
+    // The triangles of each mesh, in the mesh's local space.
+    let mut mesh_triangles = Vec::with_capacity(document.meshes().len());
+
+    progress(1.0 / steps);
+
+    for (mesh_index, mesh) in document.meshes().enumerate() {
+        let mut triangles = Vec::new();
+
+        for (primitive_index, primitive) in mesh.primitives().enumerate() {
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+            let positions: Vec<[f32; 3]> = reader.read_positions()
+                .ok_or_else(|| format!("a primitive of mesh {} has no positions", mesh_index))?
+                .collect();
+            let texcoords: Option<Vec<[f32; 2]>> = reader.read_tex_coords(0)
+                .map(|texcoords| texcoords.into_f32().collect());
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+
+            if indices.iter().any(|index| *index as usize >= positions.len()) {
+                return Err(format!("a primitive of mesh {} has out of range indices", mesh_index));
+            }
+
+            if texcoords.as_ref().map(|texcoords| texcoords.len() < positions.len()).unwrap_or(false) {
+                return Err(format!("a primitive of mesh {} has fewer texture coordinates than positions", mesh_index));
+            }
+
+            let vertex_indices: Vec<[u32; 3]> = match primitive.mode() {
+                Mode::Triangles => indices.chunks_exact(3)
+                    .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+                    .collect(),
+                Mode::TriangleStrip => indices.windows(3)
+                    .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+                    .collect(),
+                Mode::TriangleFan => indices.iter().skip(1).zip(indices.iter().skip(2))
+                    .map(|(second, third)| [indices[0], *second, *third])
+                    .collect(),
+                // Points and lines have no area to hit.
+                _ => Vec::new(),
+            };
+
+            triangles.extend(vertex_indices.into_iter().enumerate().map(|(triangle_index, [a, b, c])| {
+                let source = TriangleSource {
+                    mesh_index,
+                    primitive_index,
+                    triangle_index,
+                    texcoords: texcoords.as_ref()
+                        .map(|texcoords| [texcoords[a as usize], texcoords[b as usize], texcoords[c as usize]]),
+                };
+
+                ([positions[a as usize], positions[b as usize], positions[c as usize]], source)
+            }));
+        }
+
+        mesh_triangles.push(triangles);
+        progress((mesh_index + 2) as f32 / steps);
+    }
+
+    let mut triangles = Vec::new();
+    let identity = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+    match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(scene) => {
+            for node in scene.nodes() {
+                collect_node_triangles(&node, &identity, &mesh_triangles, &mut triangles);
+            }
+        },
+        // Without scenes, the meshes are shown as they are.
+        None => triangles.extend(mesh_triangles.into_iter().flatten()),
+    }
+
+    let (triangles, sources) = triangles.into_iter().unzip();
+
+    Ok(ModelGeometry::new(triangles, sources))
+}
+
+/// Collects the triangles of the node and its descendants, transformed into the space of the
+/// scene. The matrices are column-major, as in glTF.
+fn collect_node_triangles(
+    node: &Node,
+    parent_matrix: &[[f32; 4]; 4],
+    mesh_triangles: &[Vec<([[f32; 3]; 3], TriangleSource)>],
+    triangles: &mut Vec<([[f32; 3]; 3], TriangleSource)>,
+) {
+    let local_matrix = node.transform().matrix();
+    let mut matrix = [[0.0; 4]; 4];
+
+    for column in 0..4 {
+        for row in 0..4 {
+            matrix[column][row] = (0..4).map(|k| parent_matrix[k][row] * local_matrix[column][k]).sum();
+        }
+    }
+
+    if let Some(mesh) = node.mesh() {
+        let transform = |point: &[f32; 3]| {
+            let mut result = [0.0; 3];
+
+            for row in 0..3 {
+                result[row] = matrix[0][row] * point[0] + matrix[1][row] * point[1] + matrix[2][row] * point[2] + matrix[3][row];
+            }
+
+            result
+        };
+
+        triangles.extend(mesh_triangles[mesh.index()].iter()
+            .map(|(triangle, source)| ([transform(&triangle[0]), transform(&triangle[1]), transform(&triangle[2])], *source)));
+    }
+
+    for child in node.children() {
+        collect_node_triangles(&child, &matrix, mesh_triangles, triangles);
+    }
+}
+
+/// Decodes the buffers embedded as base64 data URIs. Models are loaded from memory, so other
+/// URIs cannot be resolved.
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, String> {
+    const BASE64_MARKER: &str = ";base64,";
+
+    match uri.find(BASE64_MARKER) {
+        Some(marker) if uri.starts_with("data:") => base64::decode(&uri[marker + BASE64_MARKER.len()..])
+            .map_err(|error| format!("invalid buffer data: {}", error)),
+        _ => Err(format!("the external buffer {} cannot be resolved", uri)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_requests_are_served_from_the_cache() {
+        let mut cache = ModelCache::new();
+        let bytes = b"not a glTF document".to_vec();
+        let first = cache.request(bytes.clone());
+        let second = cache.request(bytes);
+
+        match (first, second) {
+            (ModelRequest::Pending(first), ModelRequest::Pending(second)) => assert_eq!(first, second),
+            _ => panic!("the model was loaded without an upload"),
+        }
+
+        assert_eq!(cache.misses(), 1);
+        assert_eq!(cache.hits(), 1);
+
+        cache.request(b"another document".to_vec());
+
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(cache.hits(), 1);
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+use std::time::{Instant, Duration, SystemTime};
+
+/// Detects modifications of a file on disk by polling its modification time.
+pub struct FileWatcher {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+    pending_modified: Option<SystemTime>,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    /// How often the file's metadata is queried.
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let last_modified = Self::query_modified(&path);
+
+        Self {
+            path,
+            last_modified,
+            pending_modified: None,
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn query_modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Returns `true` once the file has been modified and then left unchanged for a whole
+    /// poll interval, so that a module which is still being written is not picked up.
+    pub fn poll(&mut self) -> bool {
+        if self.last_poll.elapsed() < Self::POLL_INTERVAL {
+            return false;
+        }
+
+        self.last_poll = Instant::now();
+
+        let modified = Self::query_modified(&self.path);
+
+        if modified.is_none() || modified == self.last_modified {
+            self.pending_modified = None;
+            return false;
+        }
+
+        if modified != self.pending_modified {
+            self.pending_modified = modified;
+            return false;
+        }
+
+        self.last_modified = modified;
+        self.pending_modified = None;
+
+        true
+    }
+}
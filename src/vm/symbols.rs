@@ -0,0 +1,261 @@
+//! Resolves the locations of traps to the functions of a wasm module, so that the crash of a mapp
+//! can be reported along with the function it occurred in.
+//!
+//! TODO: wasmtime 0.8, which the host bindings are generated for, reports the trapping instruction
+//! only by its offset in the message of the trap, so only the innermost frame is known. Report the
+//! whole backtrace of the trap instead, once mlib moves to a wasmtime release providing one.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use super::fuel::{Reader, InstrumentationError};
+
+const SECTION_CUSTOM: u8 = 0;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_CODE: u8 = 10;
+
+const EXTERNAL_FUNCTION: u8 = 0x00;
+const EXTERNAL_TABLE: u8 = 0x01;
+const EXTERNAL_MEMORY: u8 = 0x02;
+const EXTERNAL_GLOBAL: u8 = 0x03;
+
+const NAME_SECTION: &[u8] = b"name";
+const NAME_SUBSECTION_FUNCTIONS: u8 = 1;
+
+/// Precedes the hexadecimal module offset of the trapping instruction in wasmtime's trap messages.
+const SOURCE_LOCATION_PREFIX: &str = "source location: @";
+
+/// A frame of a wasm call stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmFrame {
+    pub function_index: u32,
+    /// The name of the function given by the name section of the module, if any.
+    pub function_name: Option<String>,
+    /// The offset of the instruction within the module.
+    pub module_offset: usize,
+}
+
+impl fmt::Display for WasmFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function_name {
+            Some(function_name) => write!(f, "{}", function_name)?,
+            None => write!(f, "<unnamed>")?,
+        }
+
+        write!(f, " (function {}, offset 0x{:x})", self.function_index, self.module_offset)
+    }
+}
+
+/// The code ranges and the names of the functions of a module.
+#[derive(Debug, Default)]
+pub struct FunctionSymbols {
+    /// The ranges of the function bodies within the module, in ascending order, along with the
+    /// indices of the functions.
+    bodies: Vec<(Range<usize>, u32)>,
+    names: HashMap<u32, String>,
+}
+
+impl FunctionSymbols {
+    /// Reads the symbols of a module. A malformed module has no symbols.
+    pub fn parse(bytes: &[u8]) -> Self {
+        Self::try_parse(bytes).unwrap_or_default()
+    }
+
+    fn try_parse(bytes: &[u8]) -> Result<Self, InstrumentationError> {
+        let mut reader = Reader::new(bytes);
+        let mut symbols = Self::default();
+        let mut imported_functions = 0;
+
+        reader.read_bytes(8)?;
+
+        while !reader.is_empty() {
+            let id = reader.read_byte()?;
+            let length = reader.read_u32()? as usize;
+            let start = reader.position;
+            let mut section = Reader::at(reader.read_bytes(length)?, start);
+
+            match id {
+                SECTION_IMPORT => {
+                    for _ in 0..section.read_u32()? {
+                        section.read_name()?;
+                        section.read_name()?;
+
+                        match section.read_byte()? {
+                            EXTERNAL_FUNCTION => {
+                                section.read_u32()?;
+                                imported_functions += 1;
+                            },
+                            EXTERNAL_TABLE => {
+                                section.read_byte()?;
+                                section.skip_limits()?;
+                            },
+                            EXTERNAL_MEMORY => section.skip_limits()?,
+                            EXTERNAL_GLOBAL => { section.read_bytes(2)?; },
+                            _ => return Err(section.error("unsupported import kind")),
+                        }
+                    }
+                },
+                SECTION_CODE => {
+                    for function in 0..section.read_u32()? {
+                        let length = section.read_u32()? as usize;
+                        let body_start = section.position;
+
+                        section.read_bytes(length)?;
+                        symbols.bodies.push((body_start..section.position, imported_functions + function));
+                    }
+                },
+                // The name section is only a debugging aid, so the functions are kept, even if
+                // their names cannot be read.
+                SECTION_CUSTOM if section.read_name()? == NAME_SECTION => {
+                    Self::read_names(section, &mut symbols.names).ok();
+                },
+                _ => (),
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    fn read_names(mut section: Reader, names: &mut HashMap<u32, String>) -> Result<(), InstrumentationError> {
+        while !section.is_empty() {
+            let subsection_id = section.read_byte()?;
+            let subsection_length = section.read_u32()? as usize;
+            let subsection_start = section.position;
+            let mut subsection = Reader::at(section.read_bytes(subsection_length)?, subsection_start);
+
+            if subsection_id != NAME_SUBSECTION_FUNCTIONS {
+                continue;
+            }
+
+            for _ in 0..subsection.read_u32()? {
+                let index = subsection.read_u32()?;
+                let name = String::from_utf8_lossy(subsection.read_name()?).into_owned();
+
+                names.insert(index, name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the frame of the instruction at the offset within the module, if it belongs to
+    /// a function body.
+    pub fn resolve(&self, module_offset: usize) -> Option<WasmFrame> {
+        let body = self.bodies.binary_search_by(|(range, _)| {
+            if range.end <= module_offset {
+                Ordering::Less
+            } else if range.start > module_offset {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        }).ok()?;
+        let function_index = self.bodies[body].1;
+
+        Some(WasmFrame {
+            function_index,
+            function_name: self.names.get(&function_index).cloned(),
+            module_offset,
+        })
+    }
+
+    /// Returns the frame of the trapping instruction, if the message of the trap includes its
+    /// source location.
+    pub fn resolve_trap(&self, trap_message: &str) -> Option<WasmFrame> {
+        let location_start = trap_message.find(SOURCE_LOCATION_PREFIX)? + SOURCE_LOCATION_PREFIX.len();
+        let location = &trap_message[location_start..];
+        let location_end = location.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(location.len());
+        let module_offset = usize::from_str_radix(&location[..location_end], 16).ok()?;
+
+        self.resolve(module_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(bytes: &mut Vec<u8>, mut value: u32) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+
+            value >>= 7;
+
+            if value == 0 {
+                bytes.push(byte);
+                return;
+            }
+
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn push_section(bytes: &mut Vec<u8>, code: u8, content: &[u8]) {
+        bytes.push(code);
+        push_u32(bytes, content.len() as u32);
+        bytes.extend_from_slice(content);
+    }
+
+    /// Encodes a module importing a function `env.log` and defining two functions `() -> ()`,
+    /// the second of which is named `spin`. Returns the module along with the offsets of
+    /// the bodies of the defined functions.
+    fn module() -> (Vec<u8>, Vec<usize>) {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        let mut name_section = Vec::new();
+        let mut function_names = Vec::new();
+
+        push_section(&mut bytes, 1, &[0x01, 0x60, 0x00, 0x00]);
+        push_section(&mut bytes, 2, &[0x01, 0x03, b'e', b'n', b'v', 0x03, b'l', b'o', b'g', EXTERNAL_FUNCTION, 0x00]);
+        push_section(&mut bytes, 3, &[0x02, 0x00, 0x00]);
+
+        // The section header is followed by the function count, and each body by its size
+        let code_start = bytes.len() + 2;
+        let body_offsets = vec![code_start + 2, code_start + 2 + 4];
+
+        push_section(&mut bytes, SECTION_CODE, &[0x02, 0x03, 0x00, 0x01, 0x0b, 0x05, 0x00, 0x03, 0x40, 0x0b, 0x0b]);
+
+        push_u32(&mut name_section, NAME_SECTION.len() as u32);
+        name_section.extend_from_slice(NAME_SECTION);
+        function_names.extend_from_slice(&[0x01, 0x02, 0x04, b's', b'p', b'i', b'n']);
+        name_section.push(NAME_SUBSECTION_FUNCTIONS);
+        push_u32(&mut name_section, function_names.len() as u32);
+        name_section.extend_from_slice(&function_names);
+        push_section(&mut bytes, SECTION_CUSTOM, &name_section);
+
+        (bytes, body_offsets)
+    }
+
+    #[test]
+    fn offsets_are_resolved_to_functions() {
+        let (bytes, body_offsets) = module();
+        let symbols = FunctionSymbols::parse(&bytes);
+
+        assert_eq!(symbols.resolve(body_offsets[0] + 1), Some(WasmFrame {
+            function_index: 1,
+            function_name: None,
+            module_offset: body_offsets[0] + 1,
+        }));
+        assert_eq!(symbols.resolve(body_offsets[1]).and_then(|frame| frame.function_name), Some("spin".to_string()));
+        assert_eq!(symbols.resolve(body_offsets[1] + 5), None);
+        assert_eq!(symbols.resolve(0), None);
+    }
+
+    #[test]
+    fn trap_messages_are_resolved_by_their_source_location() {
+        let (bytes, body_offsets) = module();
+        let symbols = FunctionSymbols::parse(&bytes);
+        let message = format!("wasm trap: unreachable, source location: @{:04x}", body_offsets[1] + 2);
+
+        assert_eq!(symbols.resolve_trap(&message).map(|frame| frame.function_index), Some(2));
+        assert_eq!(symbols.resolve_trap("wasm trap: unreachable, source location: @-"), None);
+        assert_eq!(symbols.resolve_trap("explicit panic"), None);
+    }
+
+    #[test]
+    fn malformed_modules_have_no_symbols() {
+        let symbols = FunctionSymbols::parse(b"\0asm\x01\0\0\0\x0a\x05\x01");
+
+        assert_eq!(symbols.resolve(10), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::any::Any;
+use std::fmt;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, Duration};
+use ::mlib::*;
+use super::symbols::WasmFrame;
+
+/// Worker threads are named with this prefix followed by the mapp's name.
+const THREAD_NAME_PREFIX: &str = "mapp ";
+
+/// How long dropping a worker waits for its thread to finish the request it is handling.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A trap or a panic that occurred while running a mapp.
+#[derive(Debug)]
+pub struct MappCrash {
+    pub message: String,
+    /// The wasm frames of the trap, innermost first, as far as they are known, see `vm::symbols`.
+    pub frames: Vec<WasmFrame>,
+}
+
+impl MappCrash {
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Unknown panic payload.".to_string()
+        };
+
+        Self {
+            message,
+            frames: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for MappCrash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        for frame in &self.frames {
+            write!(f, "\n    at {}", frame)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Messages sent from the host to a mapp's worker thread.
+pub enum MappRequest {
+    Update(Duration),
+    Event(Event),
+    CommandResponse(CommandResponse),
+    Shutdown,
+}
+
+/// Messages sent from a mapp's worker thread to the host.
+pub enum MappReply {
+    Command(Command),
+    /// The worker has finished handling a request and forwarded all resulting commands.
+    Idle,
+    /// The mapp trapped or panicked; the worker thread has terminated.
+    Crashed(MappCrash),
+    /// The mapp could not be constructed, for the given reason; the worker thread has
+    /// terminated.
+    Failed(String),
+}
+
+/// Runs a mapp on a thread of its own, exchanging requests and commands with the host
+/// over channels.
+pub struct MappWorker {
+    requests: Sender<MappRequest>,
+    replies: Receiver<MappReply>,
+    thread: Option<JoinHandle<()>>,
+    /// The number of requests the worker has not finished handling yet.
+    pending_requests: usize,
+}
+
+impl MappWorker {
+    /// Spawns a worker thread and constructs the mapp on it.
+    /// The mapp is constructed on the worker thread, because wasm instances cannot be sent
+    /// across threads. Returns without waiting for the construction: the worker is busy until
+    /// the mapp is ready, see `is_busy`, and the requests sent in the meantime are queued.
+    ///
+    /// An error of the factory is reported as `MappReply::Failed`, and a trap during the
+    /// construction as `MappReply::Crashed`.
+    pub fn spawn<E: fmt::Display + Send + 'static>(
+        name: &str,
+        factory: impl FnOnce() -> Result<Box<dyn MappInterface>, E> + Send + 'static,
+    ) -> Self {
+        let (request_sender, request_receiver) = channel();
+        let (reply_sender, reply_receiver) = channel();
+
+        let thread = thread::Builder::new()
+            .name(format!("{}{}", THREAD_NAME_PREFIX, name))
+            .spawn(move || {
+                let mapp = match panic::catch_unwind(AssertUnwindSafe(factory)) {
+                    Ok(Ok(mapp)) => mapp,
+                    Ok(Err(error)) => {
+                        reply_sender.send(MappReply::Failed(error.to_string())).ok();
+                        return;
+                    },
+                    Err(payload) => {
+                        reply_sender.send(MappReply::Crashed(MappCrash::from_panic(payload))).ok();
+                        return;
+                    },
+                };
+
+                Self::run(mapp, request_receiver, reply_sender);
+            })
+            .expect("Could not spawn a mapp worker thread.");
+
+        Self {
+            requests: request_sender,
+            replies: reply_receiver,
+            thread: Some(thread),
+            // The construction, and the commands sent during it, are followed by an `Idle` reply
+            pending_requests: 1,
+        }
+    }
+
+    fn run(mut mapp: Box<dyn MappInterface>, requests: Receiver<MappRequest>, replies: Sender<MappReply>) {
+        let mut request = None;
+
+        loop {
+            // Traps are raised as panics by the wasm bindings; catch them here, so that they
+            // are reported to the host instead of silently terminating the thread.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                match request.take() {
+                    Some(MappRequest::Update(elapsed)) => mapp.update(elapsed),
+                    Some(MappRequest::Event(event)) => mapp.receive_event(event),
+                    Some(MappRequest::CommandResponse(response)) => mapp.receive_command_response(response),
+                    Some(MappRequest::Shutdown) | None => (),
+                }
+
+                Self::forward_output(&mut *mapp, &replies)
+            }));
+
+            match result {
+                Ok(Ok(())) => (),
+                // The host has dropped the worker
+                Ok(Err(())) => break,
+                Err(payload) => {
+                    replies.send(MappReply::Crashed(MappCrash::from_panic(payload))).ok();
+                    break;
+                },
+            }
+
+            match requests.recv() {
+                Ok(MappRequest::Shutdown) | Err(_) => break,
+                Ok(next_request) => request = Some(next_request),
+            }
+        }
+    }
+
+    /// Sends the queued commands to the host and redirects the mapp's IO to the host's.
+    fn forward_output(mapp: &mut dyn MappInterface, replies: &Sender<MappReply>) -> Result<(), ()> {
+        while let Some(command) = mapp.send_command() {
+            replies.send(MappReply::Command(command)).map_err(|_| ())?;
+        }
+
+        let IO { out, err } = mapp.flush_io();
+
+        if !out.is_empty() {
+            let stdout = std::io::stdout();
+            let mut handle = stdout.lock();
+
+            handle.write_all(&out[..])
+                .expect("Could not redirect the module's stdout to the host stdout.");
+        }
+
+        if !err.is_empty() {
+            let stderr = std::io::stderr();
+            let mut handle = stderr.lock();
+
+            handle.write_all(&err[..])
+                .expect("Could not redirect the module's stderr to the host stderr.");
+        }
+
+        replies.send(MappReply::Idle).map_err(|_| ())
+    }
+
+    pub fn send(&mut self, request: MappRequest) {
+        if self.requests.send(request).is_ok() {
+            self.pending_requests += 1;
+        }
+    }
+
+    /// Returns the next reply of the worker without blocking.
+    pub fn try_recv(&mut self) -> Result<MappReply, TryRecvError> {
+        let reply = self.replies.try_recv()?;
+
+        if let MappReply::Idle = reply {
+            self.pending_requests = self.pending_requests.saturating_sub(1);
+        }
+
+        Ok(reply)
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.pending_requests > 0
+    }
+}
+
+impl Drop for MappWorker {
+    /// Shuts the worker down and joins its thread. A mapp that does not return control to the
+    /// worker within `SHUTDOWN_TIMEOUT` is left to run out of fuel, see `vm::fuel`, so its
+    /// thread is detached instead.
+    fn drop(&mut self) {
+        self.requests.send(MappRequest::Shutdown).ok();
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+
+        // The worker drops its end of the reply channel once its thread terminates.
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            match self.replies.recv_timeout(timeout) {
+                Ok(_) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(thread) = self.thread.take() {
+                        eprintln!(
+                            "The worker thread {:?} did not shut down within {:.3} seconds, detaching it.",
+                            thread.thread().name().unwrap_or_default(), SHUTDOWN_TIMEOUT.as_secs_f32(),
+                        );
+                    }
+
+                    return;
+                },
+            }
+        }
+
+        if let Some(thread) = self.thread.take() {
+            // Panics are caught and reported by the worker itself
+            thread.join().ok();
+        }
+    }
+}